@@ -1,13 +1,167 @@
 use {
     anyhow::Result,
-    serde::{de::DeserializeOwned, Deserialize, Serialize},
+    serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer},
+    serde_json::Value,
     std::io,
-    tokio::sync::Mutex,
+    tokio::{
+        io::{AsyncBufRead, AsyncWrite},
+        sync::Mutex,
+    },
 };
 
-type MessageReader = dyn tokio::io::AsyncBufRead + Unpin + Send;
+pub mod transport;
 
-pub async fn write_msg(out: &Mutex<tokio::io::Stdout>, req: impl Serialize) -> Result<()> {
+pub use transport::Transport;
+
+/// Error codes defined by JSON-RPC 2.0, and the ones LSP adds on top.
+pub mod codes {
+    pub const PARSE_ERROR: i32 = -32700;
+    pub const INVALID_REQUEST: i32 = -32600;
+    pub const METHOD_NOT_FOUND: i32 = -32601;
+    pub const INVALID_PARAMS: i32 = -32602;
+    pub const INTERNAL_ERROR: i32 = -32603;
+
+    /// Start of the range reserved for implementation-defined server errors.
+    pub const SERVER_ERROR: i32 = -32000;
+
+    pub const REQUEST_CANCELLED: i32 = -32800;
+}
+
+/// The `"jsonrpc": "2.0"` member, which must be exactly `"2.0"`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Version;
+
+impl Serialize for Version {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str("2.0")
+    }
+}
+
+impl<'de> Deserialize<'de> for Version {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match String::deserialize(deserializer)?.as_str() {
+            "2.0" => Ok(Version),
+            other => Err(serde::de::Error::custom(format!(
+                "unsupported jsonrpc version: {other}"
+            ))),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum Id {
+    Number(i64),
+    String(String),
+}
+
+impl From<usize> for Id {
+    fn from(id: usize) -> Self {
+        Self::Number(id as i64)
+    }
+}
+
+impl std::fmt::Display for Id {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Number(id) => write!(f, "{id}"),
+            Self::String(id) => write!(f, "{id:?}"),
+        }
+    }
+}
+
+/// A method call without a typed method, i.e. `{ "method": ..., "params": ... }`.
+///
+/// Typed calls are usually enums with `#[serde(tag = "method", content = "params")]`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RawCall {
+    pub method: String,
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub params: Value,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Request<T = RawCall> {
+    pub jsonrpc: Version,
+    pub id: Id,
+    #[serde(flatten)]
+    pub call: T,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Notification<T = RawCall> {
+    pub jsonrpc: Version,
+    #[serde(flatten)]
+    pub call: T,
+}
+
+impl<T> Notification<T> {
+    pub fn new(call: T) -> Self {
+        Self {
+            jsonrpc: Version,
+            call,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Outcome<R> {
+    #[serde(rename = "result")]
+    Result(R),
+    #[serde(rename = "error")]
+    Error(RPCErr),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Response<R = Value> {
+    pub jsonrpc: Version,
+
+    /// `None` only when the id of the request could not be read (e.g. a parse error).
+    pub id: Option<Id>,
+
+    #[serde(flatten)]
+    pub outcome: Outcome<R>,
+}
+
+impl<R> Response<R> {
+    pub fn ok(id: impl Into<Id>, result: R) -> Self {
+        Self {
+            jsonrpc: Version,
+            id: Some(id.into()),
+            outcome: Outcome::Result(result),
+        }
+    }
+
+    pub fn err(id: Option<Id>, err: RPCErr) -> Self {
+        Self {
+            jsonrpc: Version,
+            id,
+            outcome: Outcome::Error(err),
+        }
+    }
+}
+
+/// A single message, or a batch of them sent as an array.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Batch<T> {
+    Many(Vec<T>),
+    One(T),
+}
+
+impl<T> Batch<T> {
+    pub fn into_vec(self) -> Vec<T> {
+        match self {
+            Self::Many(messages) => messages,
+            Self::One(message) => vec![message],
+        }
+    }
+}
+
+pub async fn write_msg<W>(out: &Mutex<W>, req: impl Serialize) -> Result<()>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
     use tokio::io::AsyncWriteExt;
     let msg = serde_json::to_string(&req)?;
     let header = format!("Content-Length: {}\r\n\r\n", msg.len());
@@ -20,29 +174,60 @@ pub async fn write_msg(out: &Mutex<tokio::io::Stdout>, req: impl Serialize) -> R
     Ok(())
 }
 
+/// The error object of a response.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RPCErr {
     pub code: i32,
     pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
 }
 
-pub async fn write_err(out: &Mutex<tokio::io::Stdout>, err: RPCErr) -> Result<()> {
-    use tokio::io::AsyncWriteExt;
+impl RPCErr {
+    pub fn new(code: i32, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
 
-    let msg = serde_json::to_string(&err)?;
-    let header = format!("Content-Length: {}\r\n\r\n", msg.len());
+    pub fn with_data(self, data: Value) -> Self {
+        Self {
+            data: Some(data),
+            ..self
+        }
+    }
+}
 
-    let out = &mut *out.lock().await;
-    out.write_all(header.as_bytes()).await?;
-    out.write_all(msg.as_bytes()).await?;
-    out.flush().await?;
+impl std::fmt::Display for RPCErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
 
-    Ok(())
+/// Handlers can fail with an `RPCErr` (for example through `anyhow`) to choose the error code.
+impl std::error::Error for RPCErr {}
+
+/// Reply to the request with `id` with an error.
+pub async fn write_err<W>(out: &Mutex<W>, id: Option<Id>, err: RPCErr) -> Result<()>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    write_msg(out, Response::<Value>::err(id, err)).await
+}
+
+/// Find the id of a message that could not be parsed, so the error can be sent to the right
+/// request.
+pub fn find_id(text: &str) -> Option<Id> {
+    let value: Value = serde_json::from_str(text).ok()?;
+    serde_json::from_value(value.get("id")?.clone()).ok()
 }
 
-pub async fn read_msg<T>(r: &mut MessageReader) -> Result<Option<T>>
+pub async fn read_msg<T, R>(r: &mut R) -> Result<Option<T>>
 where
     T: DeserializeOwned,
+    R: AsyncBufRead + Unpin + ?Sized,
 {
     let text = match read_msg_text(r).await? {
         None => return Ok(None),
@@ -52,7 +237,10 @@ where
     Ok(Some(msg))
 }
 
-pub async fn read_msg_text(inp: &mut MessageReader) -> Result<Option<String>> {
+pub async fn read_msg_text<R>(inp: &mut R) -> Result<Option<String>>
+where
+    R: AsyncBufRead + Unpin + ?Sized,
+{
     use tokio::io::{AsyncBufReadExt, AsyncReadExt};
 
     fn invalid_data(error: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
@@ -0,0 +1,94 @@
+//! The streams messages are read from and written to.
+//!
+//! Messages use the same `Content-Length` framing over any byte stream, so the editor can talk to
+//! the agent over stdio, a Unix socket or TCP, and tests can talk to it in memory.
+
+use {
+    std::sync::Arc,
+    tokio::{
+        io::{AsyncBufRead, AsyncRead, AsyncWrite, BufReader},
+        sync::Mutex,
+    },
+};
+
+pub type Reader = Box<dyn AsyncBufRead + Unpin + Send>;
+
+/// Shared, so that responses and notifications from different tasks don't interleave.
+pub type Writer = Arc<Mutex<Box<dyn AsyncWrite + Unpin + Send>>>;
+
+pub struct Transport {
+    pub reader: Reader,
+    pub writer: Writer,
+}
+
+impl Transport {
+    pub fn new<R, W>(read: R, write: W) -> Self
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        Self {
+            reader: Box::new(BufReader::new(read)),
+            writer: Arc::new(Mutex::new(Box::new(write))),
+        }
+    }
+
+    pub fn stdio() -> Self {
+        Self::new(tokio::io::stdin(), tokio::io::stdout())
+    }
+
+    /// Use a bidirectional stream, such as a `UnixStream` or `TcpStream`.
+    pub fn from_stream<S>(stream: S) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (read, write) = tokio::io::split(stream);
+        Self::new(read, write)
+    }
+
+    /// Two transports connected to each other, for running a client and server in one process.
+    pub fn memory() -> (Self, Self) {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        (Self::from_stream(client), Self::from_stream(server))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{read_msg, write_msg, Id, Request, Response},
+        serde_json::{json, Value},
+    };
+
+    #[tokio::test]
+    async fn messages_round_trip_in_memory() {
+        let (mut client, mut server) = Transport::memory();
+
+        let request = json!({ "jsonrpc": "2.0", "id": 1, "method": "echo", "params": {} });
+        write_msg(&client.writer, &request).await.unwrap();
+
+        let received: Request = read_msg(&mut server.reader).await.unwrap().unwrap();
+        assert_eq!(received.id, Id::Number(1));
+        assert_eq!(received.call.method, "echo");
+
+        write_msg(&server.writer, Response::ok(received.id, "hello"))
+            .await
+            .unwrap();
+
+        let response: Value = read_msg(&mut client.reader).await.unwrap().unwrap();
+        assert_eq!(
+            response,
+            json!({ "jsonrpc": "2.0", "id": 1, "result": "hello" })
+        );
+    }
+
+    #[tokio::test]
+    async fn closed_stream_ends_reading() {
+        let (client, mut server) = Transport::memory();
+        drop(client);
+
+        let message: Option<Value> = read_msg(&mut server.reader).await.unwrap();
+        assert!(message.is_none());
+    }
+}
@@ -1,4 +1,11 @@
-use {anyhow::Result, graphql_client::GraphQLQuery, sg_types::CodyMessage};
+use {
+    crate::completions_stream::{
+        DEFAULT_MAX_TOKENS, DEFAULT_TEMPERATURE, DEFAULT_TOP_K, DEFAULT_TOP_P,
+    },
+    anyhow::Result,
+    graphql_client::GraphQLQuery,
+    sg_types::{CodyMessage, CompletionParameters},
+};
 
 pub(super) mod private {
     use super::*;
@@ -7,17 +14,20 @@ pub(super) mod private {
     #[graphql(
         schema_path = "gql/schema.gql",
         query_path = "gql/completions_query.gql",
-        response_derives = "Debug"
+        response_derives = "Debug",
+        variables_derives = "Clone"
     )]
     pub struct CompletionQuery;
 }
 
 pub use private::CompletionQuery as Query;
 
+/// The GraphQL API always uses the instance's chat model, and has no stop sequences, so those
+/// parameters are ignored here.
 #[derive(Debug)]
 pub struct Variables {
     pub messages: Vec<CodyMessage>,
-    pub temperature: Option<f64>,
+    pub parameters: CompletionParameters,
 }
 
 impl From<Variables> for private::completion_query::Variables {
@@ -25,7 +35,7 @@ impl From<Variables> for private::completion_query::Variables {
         use private::completion_query::{Message, SpeakerType};
         let Variables {
             messages,
-            temperature,
+            parameters,
         } = val;
 
         let messages = messages
@@ -41,20 +51,22 @@ impl From<Variables> for private::completion_query::Variables {
 
         Self {
             messages,
-            temperature: temperature.unwrap_or(0.5),
-            max_tokens_to_sample: 1000,
-            top_k: -1,
-            top_p: -1,
+            temperature: parameters.temperature.unwrap_or(DEFAULT_TEMPERATURE),
+            max_tokens_to_sample: parameters.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+            top_k: parameters.top_k.unwrap_or(DEFAULT_TOP_K),
+            // The GraphQL API only takes a whole number for top-p, which can't be a probability,
+            // so completions that set it are streamed instead (see `complete_messages`)
+            top_p: DEFAULT_TOP_P as i64,
         }
     }
 }
 
 pub async fn request(
-    client: &reqwest::Client,
+    client: reqwest::Client,
     headers: reqwest::header::HeaderMap,
     endpoint: String,
     variables: private::completion_query::Variables,
 ) -> Result<String> {
-    let response = crate::get_graphql::<Query>(client, headers, endpoint, variables).await?;
+    let response = crate::get_graphql::<Query>(&client, headers, endpoint, variables).await?;
     Ok(response.completions)
 }
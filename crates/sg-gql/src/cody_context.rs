@@ -0,0 +1,129 @@
+//! Context for Cody from `getCodyContext`, which replaces `embeddingsSearch` (see
+//! [`embeddings_context`](crate::embeddings_context)) on newer instances and can search several
+//! repositories at once.
+
+use {
+    anyhow::Result,
+    graphql_client::GraphQLQuery,
+    sg_types::{Embedding, SourcegraphVersion},
+};
+
+pub(super) mod private {
+    use super::*;
+
+    #[derive(GraphQLQuery)]
+    #[graphql(
+        schema_path = "gql/schema.gql",
+        query_path = "gql/cody_context.gql",
+        response_derives = "Debug",
+        variables_derives = "Clone"
+    )]
+    pub struct CodyContextQuery;
+}
+
+pub use private::{cody_context_query::Variables, CodyContextQuery as Query};
+
+/// The first release with `getCodyContext`.
+const FIRST_VERSION: (u64, u64) = (5, 1);
+
+/// Files that `getCodyContext` counts as text results rather than code.
+const TEXT_EXTENSIONS: &[&str] = &["md", "markdown", "mdx", "txt", "rst", "adoc", "org"];
+
+/// Whether the instance has `getCodyContext`. Insiders and development builds don't have a
+/// release version, and are always new enough.
+pub fn is_supported(version: &SourcegraphVersion) -> bool {
+    let mut parts = version
+        .product
+        .split(['.', '-', '+'])
+        .map(|part| part.parse::<u64>());
+
+    match (parts.next(), parts.next()) {
+        // Development builds are `0.0.0+dev`
+        (Some(Ok(0)), Some(Ok(0))) => true,
+        (Some(Ok(major)), Some(Ok(minor))) => (major, minor) >= FIRST_VERSION,
+        _ => true,
+    }
+}
+
+pub async fn request(
+    client: reqwest::Client,
+    headers: reqwest::header::HeaderMap,
+    endpoint: String,
+    variables: Variables,
+) -> Result<Vec<Embedding>> {
+    use private::cody_context_query::CodyContextQueryGetCodyContext::*;
+    let response = crate::get_graphql::<Query>(&client, headers, endpoint, variables).await?;
+
+    Ok(response
+        .get_cody_context
+        .into_iter()
+        .map(|result| match result {
+            FileChunkContext(chunk) => {
+                let repo = chunk.blob.repository.name;
+                let file = chunk.blob.path;
+                let start = chunk.start_line as usize;
+                let finish = chunk.end_line as usize;
+                let content = chunk.chunk_content;
+
+                if is_text(&file) {
+                    Embedding::Text {
+                        repo,
+                        file,
+                        start,
+                        finish,
+                        content,
+                    }
+                } else {
+                    Embedding::Code {
+                        repo,
+                        file,
+                        start,
+                        finish,
+                        content,
+                    }
+                }
+            }
+        })
+        .collect())
+}
+
+fn is_text(file: &str) -> bool {
+    std::path::Path::new(file)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| TEXT_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(product: &str) -> SourcegraphVersion {
+        SourcegraphVersion {
+            product: product.to_string(),
+            build: String::new(),
+        }
+    }
+
+    #[test]
+    fn supported_from_5_1() {
+        assert!(!is_supported(&version("5.0.6")));
+        assert!(!is_supported(&version("4.5.1")));
+        assert!(is_supported(&version("5.1.0")));
+        assert!(is_supported(&version("5.2.3")));
+        assert!(is_supported(&version("6.0.0")));
+    }
+
+    #[test]
+    fn supported_on_unreleased_builds() {
+        assert!(is_supported(&version("0.0.0+dev")));
+        assert!(is_supported(&version("245874_2023-10-30_5.2-c9f2b6a31ea2")));
+    }
+
+    #[test]
+    fn markdown_is_text() {
+        assert!(is_text("docs/README.md"));
+        assert!(!is_text("src/main.rs"));
+        assert!(!is_text("Makefile"));
+    }
+}
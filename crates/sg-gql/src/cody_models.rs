@@ -0,0 +1,61 @@
+use {
+    anyhow::{Context, Result},
+    graphql_client::GraphQLQuery,
+    sg_types::{CodyModel, ModelPurpose},
+};
+
+pub(super) mod private {
+    use super::*;
+
+    #[derive(GraphQLQuery)]
+    #[graphql(
+        schema_path = "gql/schema.gql",
+        query_path = "gql/cody_models_query.gql",
+        response_derives = "Debug",
+        variables_derives = "Clone"
+    )]
+    pub struct CodyModelsQuery;
+}
+
+pub use private::{cody_models_query::Variables, CodyModelsQuery as Query};
+
+pub async fn request(
+    client: reqwest::Client,
+    headers: reqwest::header::HeaderMap,
+    endpoint: String,
+    variables: Variables,
+) -> Result<Vec<CodyModel>> {
+    let response = crate::get_graphql::<Query>(&client, headers, endpoint, variables).await?;
+    let config = response
+        .site
+        .cody_llm_configuration
+        .context("Cody is not enabled on this instance")?;
+
+    let models = [
+        (
+            ModelPurpose::Chat,
+            config.chat_model,
+            config.chat_model_max_tokens,
+        ),
+        (
+            ModelPurpose::FastChat,
+            config.fast_chat_model,
+            config.fast_chat_model_max_tokens,
+        ),
+        (
+            ModelPurpose::Completion,
+            config.completion_model,
+            config.completion_model_max_tokens,
+        ),
+    ];
+
+    Ok(models
+        .into_iter()
+        .map(|(purpose, id, max_tokens)| CodyModel {
+            id,
+            provider: config.provider.clone(),
+            purpose,
+            max_tokens,
+        })
+        .collect())
+}
@@ -11,7 +11,8 @@ pub(super) mod private {
     #[graphql(
         schema_path = "gql/schema.gql",
         query_path = "gql/commit_query.gql",
-        response_derives = "Debug"
+        response_derives = "Debug",
+        variables_derives = "Clone"
     )]
     pub struct CommitQuery;
 }
@@ -19,12 +20,12 @@ pub(super) mod private {
 pub use private::{commit_query::Variables, CommitQuery as Query};
 
 pub async fn request(
-    client: &reqwest::Client,
+    client: reqwest::Client,
     headers: reqwest::header::HeaderMap,
     endpoint: String,
     variables: Variables,
 ) -> Result<String> {
-    let response = crate::get_graphql::<Query>(client, headers, endpoint, variables).await?;
+    let response = crate::get_graphql::<Query>(&client, headers, endpoint, variables).await?;
 
     Ok(response
         .repository
@@ -0,0 +1,321 @@
+//! Streaming Cody completions from `/.api/completions/stream`.
+//!
+//! The endpoint answers with server-sent events: `completion` events carry the completion so far
+//! (or only the new text, as `deltaText`), followed by `done`, or `error` if it failed. Only the
+//! new text is passed on, so the editor can append it as it arrives. A stream that ends without
+//! `done` was cut off, and is an error.
+
+use {
+    crate::{retry, AuthError},
+    anyhow::{Context, Result},
+    reqwest::{Client, StatusCode},
+    serde::{Deserialize, Serialize},
+    sg_types::{CodyMessage, CodySpeaker, CompletionParameters},
+    std::time::Instant,
+};
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CompletionRequest {
+    pub messages: Vec<Message>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    pub temperature: f64,
+    pub max_tokens_to_sample: i64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stop_sequences: Vec<String>,
+    pub top_k: i64,
+    pub top_p: f64,
+}
+
+/// Used for parameters that aren't set. `-1` leaves top-k and top-p sampling to the model.
+pub const DEFAULT_TEMPERATURE: f64 = 0.5;
+pub const DEFAULT_MAX_TOKENS: i64 = 1000;
+pub const DEFAULT_TOP_K: i64 = -1;
+pub const DEFAULT_TOP_P: f64 = -1.0;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Message {
+    pub speaker: &'static str,
+    pub text: String,
+}
+
+impl CompletionRequest {
+    pub fn new(messages: Vec<CodyMessage>, parameters: CompletionParameters) -> Self {
+        let messages = messages
+            .into_iter()
+            .map(|msg| Message {
+                speaker: match msg.speaker {
+                    CodySpeaker::Human => "human",
+                    CodySpeaker::Assistant => "assistant",
+                },
+                text: msg.text,
+            })
+            .collect();
+
+        Self {
+            messages,
+            model: parameters.model,
+            temperature: parameters.temperature.unwrap_or(DEFAULT_TEMPERATURE),
+            max_tokens_to_sample: parameters.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+            stop_sequences: parameters.stop_sequences,
+            top_k: parameters.top_k.unwrap_or(DEFAULT_TOP_K),
+            top_p: parameters.top_p.unwrap_or(DEFAULT_TOP_P),
+        }
+    }
+}
+
+/// What a streamed completion cost. Sourcegraph doesn't report token counts, so they are
+/// estimated at four characters per token.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompletionUsage {
+    pub stop_reason: Option<String>,
+    pub chunks: usize,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub time_to_first_chunk_ms: Option<u64>,
+    pub duration_ms: u64,
+}
+
+/// Roughly how many tokens `text` is, at four characters per token.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+/// Stream a completion, calling `on_delta` with each piece of new text. Returns the whole
+/// completion and its usage.
+///
+/// Dropping the future closes the connection, which stops the completion on the server too.
+pub async fn stream(
+    client: &Client,
+    headers: reqwest::header::HeaderMap,
+    endpoint: &str,
+    request: &CompletionRequest,
+    mut on_delta: impl FnMut(&str),
+) -> Result<(String, CompletionUsage)> {
+    let started = Instant::now();
+    let url = format!("{endpoint}/.api/completions/stream");
+
+    let mut response = {
+        let _permit = retry::acquire(endpoint).await;
+        client
+            .post(&url)
+            .headers(headers)
+            .json(request)
+            .send()
+            .await
+            .with_context(|| format!("requesting {url}"))?
+    };
+
+    retry::record(endpoint, &response);
+
+    let status = response.status();
+    if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+        return Err(AuthError {
+            endpoint: endpoint.to_string(),
+            status: status.as_u16(),
+        }
+        .into());
+    }
+
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        anyhow::bail!("Rate limited by {endpoint}, try again later");
+    }
+
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        anyhow::bail!("Completion failed with status {status}: {body}");
+    }
+
+    let mut usage = CompletionUsage {
+        prompt_tokens: request
+            .messages
+            .iter()
+            .map(|msg| estimate_tokens(&msg.text))
+            .sum(),
+        ..Default::default()
+    };
+
+    let mut parser = EventParser::default();
+    let mut completion = String::new();
+    let mut done = false;
+
+    'read: while let Some(chunk) = response.chunk().await? {
+        for event in parser.push(&chunk) {
+            match event.event.as_str() {
+                "completion" => {
+                    let data: CompletionEvent = serde_json::from_str(&event.data)
+                        .with_context(|| format!("invalid completion event: {}", event.data))?;
+
+                    let delta = match (data.delta_text, data.completion) {
+                        (Some(delta), _) => delta,
+                        // The whole completion so far, so only the end of it is new
+                        (None, Some(full)) => match full.strip_prefix(completion.as_str()) {
+                            Some(delta) => delta.to_string(),
+                            None => anyhow::bail!("completion changed while streaming"),
+                        },
+                        (None, None) => String::new(),
+                    };
+
+                    if let Some(reason) = data.stop_reason.filter(|reason| !reason.is_empty()) {
+                        usage.stop_reason = Some(reason);
+                    }
+
+                    if delta.is_empty() {
+                        continue;
+                    }
+
+                    if usage.time_to_first_chunk_ms.is_none() {
+                        usage.time_to_first_chunk_ms = Some(started.elapsed().as_millis() as u64);
+                    }
+
+                    usage.chunks += 1;
+                    completion.push_str(&delta);
+                    on_delta(&delta);
+                }
+                "error" => {
+                    let data: ErrorEvent =
+                        serde_json::from_str(&event.data).unwrap_or(ErrorEvent {
+                            error: event.data.clone(),
+                        });
+                    anyhow::bail!("Completion failed: {}", data.error);
+                }
+                "done" => {
+                    done = true;
+                    break 'read;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // A connection that drops mid-completion ends the body early, which isn't a whole completion
+    if !done {
+        anyhow::bail!("Completion stream ended before it was done");
+    }
+
+    usage.completion_tokens = estimate_tokens(&completion);
+    usage.duration_ms = started.elapsed().as_millis() as u64;
+
+    Ok((completion, usage))
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct CompletionEvent {
+    completion: Option<String>,
+    delta_text: Option<String>,
+    stop_reason: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ErrorEvent {
+    error: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub event: String,
+    pub data: String,
+}
+
+/// Splits a server-sent event stream into events, as chunks of it arrive.
+#[derive(Debug, Default)]
+pub struct EventParser {
+    buffer: Vec<u8>,
+}
+
+impl EventParser {
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<Event> {
+        self.buffer.extend_from_slice(chunk);
+
+        let mut events = vec![];
+        while let Some((end, separator)) = find_blank_line(&self.buffer) {
+            let block = self.buffer.drain(..end + separator).collect::<Vec<_>>();
+            if let Some(event) = parse_event(&String::from_utf8_lossy(&block[..end])) {
+                events.push(event);
+            }
+        }
+
+        events
+    }
+}
+
+/// Find the end of the next event, which is a blank line. Returns where it starts, and how long
+/// the separator is.
+fn find_blank_line(buffer: &[u8]) -> Option<(usize, usize)> {
+    (0..buffer.len()).find_map(|i| {
+        let rest = &buffer[i..];
+        if rest.starts_with(b"\r\n\r\n") {
+            Some((i, 4))
+        } else if rest.starts_with(b"\n\n") {
+            Some((i, 2))
+        } else {
+            None
+        }
+    })
+}
+
+fn parse_event(block: &str) -> Option<Event> {
+    let mut event = "message".to_string();
+    let mut data = vec![];
+
+    for line in block.lines() {
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line, ""),
+        };
+
+        match field {
+            "event" => event = value.to_string(),
+            "data" => data.push(value),
+            // Comments (`: ...`), ids and retries don't matter for completions
+            _ => {}
+        }
+    }
+
+    if data.is_empty() {
+        return None;
+    }
+
+    Some(Event {
+        event,
+        data: data.join("\n"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(event: &str, data: &str) -> Event {
+        Event {
+            event: event.to_string(),
+            data: data.to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_events_split_across_chunks() {
+        let mut parser = EventParser::default();
+
+        assert_eq!(parser.push(b"event: completion\ndata: {\"comp"), vec![]);
+        assert_eq!(
+            parser.push(b"letion\":\"Hi\"}\n\nevent: done\r\ndata: {}\r\n\r\n"),
+            vec![
+                event("completion", "{\"completion\":\"Hi\"}"),
+                event("done", "{}")
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_comments_and_joins_data_lines() {
+        let mut parser = EventParser::default();
+
+        assert_eq!(
+            parser.push(b": keep-alive\n\ndata: one\ndata: two\n\n"),
+            vec![event("message", "one\ntwo")]
+        );
+    }
+}
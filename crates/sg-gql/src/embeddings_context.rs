@@ -7,7 +7,8 @@ pub(super) mod private {
     #[graphql(
         schema_path = "gql/schema.gql",
         query_path = "gql/embeddings_context.gql",
-        response_derives = "Debug"
+        response_derives = "Debug",
+        variables_derives = "Clone"
     )]
     pub struct EmbeddingsContextQuery;
 }
@@ -15,12 +16,12 @@ pub(super) mod private {
 pub use private::{embeddings_context_query::Variables, EmbeddingsContextQuery as Query};
 
 pub async fn request(
-    client: &reqwest::Client,
+    client: reqwest::Client,
     headers: reqwest::header::HeaderMap,
     endpoint: String,
     variables: Variables,
 ) -> Result<Vec<Embedding>> {
-    let response = crate::get_graphql::<Query>(client, headers, endpoint, variables).await?;
+    let response = crate::get_graphql::<Query>(&client, headers, endpoint, variables).await?;
 
     let mut embeddings = vec![];
     for result in response.embeddings_search.code_results {
@@ -7,7 +7,8 @@ pub(super) mod private {
     #[graphql(
         schema_path = "gql/schema.gql",
         query_path = "gql/enterprise_user_query.gql",
-        response_derives = "Debug"
+        response_derives = "Debug",
+        variables_derives = "Clone"
     )]
     pub struct EnterpriseUserQuery;
 }
@@ -16,12 +17,12 @@ pub use private::{enterprise_user_query::Variables, EnterpriseUserQuery as Query
 use {crate::dotcom_user::UserInfo, anyhow::Context};
 
 pub async fn request(
-    client: &reqwest::Client,
+    client: reqwest::Client,
     headers: reqwest::header::HeaderMap,
     endpoint: String,
     variables: Variables,
 ) -> Result<UserInfo> {
-    crate::get_graphql::<Query>(client, headers, endpoint, variables)
+    crate::get_graphql::<Query>(&client, headers, endpoint, variables)
         .await
         .and_then(|response_body| {
             let user = response_body.current_user.context("no current user")?;
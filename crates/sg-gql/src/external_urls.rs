@@ -0,0 +1,49 @@
+use {
+    anyhow::{Context, Result},
+    graphql_client::GraphQLQuery,
+    sg_types::{CodeHost, ExternalLink},
+};
+
+pub(super) mod private {
+    use super::*;
+
+    #[derive(GraphQLQuery)]
+    #[graphql(
+        schema_path = "gql/schema.gql",
+        query_path = "gql/external_urls_query.gql",
+        response_derives = "Debug",
+        variables_derives = "Clone"
+    )]
+    pub struct ExternalUrlsQuery;
+}
+
+pub use private::{external_urls_query::Variables, ExternalUrlsQuery as Query};
+
+pub async fn request(
+    client: reqwest::Client,
+    headers: reqwest::header::HeaderMap,
+    endpoint: String,
+    variables: Variables,
+) -> Result<Vec<ExternalLink>> {
+    use private::external_urls_query::ExternalServiceKind;
+
+    let response = crate::get_graphql::<Query>(&client, headers, endpoint, variables).await?;
+    let repository = response
+        .repository
+        .context("No matching repository found")?;
+
+    Ok(repository
+        .external_ur_ls
+        .into_iter()
+        .map(|link| ExternalLink {
+            url: link.url,
+            host: match link.service_kind {
+                Some(ExternalServiceKind::GITHUB) => CodeHost::GitHub,
+                Some(ExternalServiceKind::GITLAB) => CodeHost::GitLab,
+                Some(ExternalServiceKind::BITBUCKETSERVER) => CodeHost::BitbucketServer,
+                Some(ExternalServiceKind::BITBUCKETCLOUD) => CodeHost::BitbucketCloud,
+                _ => CodeHost::Other,
+            },
+        })
+        .collect())
+}
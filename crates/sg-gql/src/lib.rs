@@ -1,33 +1,114 @@
 use {
     anyhow::{Context, Result},
     graphql_client::GraphQLQuery,
-    reqwest::Client,
-    sg_types::{Remote, OID},
+    reqwest::{Client, StatusCode},
+    retry::RetryPolicy,
 };
 
 pub mod cody_completion;
+pub mod cody_context;
+pub mod cody_models;
 pub mod commit_oid;
+pub mod completions_stream;
 pub mod definition;
 pub mod dotcom_user;
 pub mod embeddings_context;
 pub mod enterprise_user;
+pub mod external_urls;
 pub mod file;
 pub mod hover;
 pub mod list_files;
 pub mod path_info;
 pub mod references;
+pub mod repository_id;
+pub mod retry;
 pub mod search;
 pub mod sourcegraph_version;
 
+/// The instance rejected the access token (HTTP 401 or 403), so the user needs to log in again.
+#[derive(Debug, Clone)]
+pub struct AuthError {
+    pub endpoint: String,
+    pub status: u16,
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Access token was rejected by {} (status {}). Log in again",
+            self.endpoint, self.status
+        )
+    }
+}
+
+impl std::error::Error for AuthError {}
+
 async fn post_graphql<Q: GraphQLQuery, U: reqwest::IntoUrl>(
     client: &reqwest::Client,
     headers: reqwest::header::HeaderMap,
     url: U,
     variables: Q::Variables,
 ) -> Result<graphql_client::Response<Q::ResponseData>> {
+    let url = url.into_url()?;
+    let endpoint = url.origin().ascii_serialization();
     let body = Q::build_query(variables);
 
-    let reqwest_response = client.post(url).headers(headers).json(&body).send().await?;
+    // Queries can be sent again safely, mutations can't
+    let idempotent = !body.query.trim_start().starts_with("mutation");
+    let policy = RetryPolicy::default();
+
+    let mut attempt = 0;
+    let reqwest_response = loop {
+        attempt += 1;
+
+        let sent = {
+            let _permit = retry::acquire(&endpoint).await;
+            client
+                .post(url.clone())
+                .headers(headers.clone())
+                .json(&body)
+                .send()
+                .await
+        };
+
+        let (retry_after, result) = match sent {
+            Ok(response) => {
+                retry::record(&endpoint, &response);
+                if !retry::is_retryable(response.status()) {
+                    break response;
+                }
+
+                (retry::retry_after(response.headers()), Ok(response))
+            }
+            Err(err) if err.is_connect() || err.is_timeout() => (None, Err(err)),
+            Err(err) => return Err(err.into()),
+        };
+
+        let delay = match idempotent {
+            true => policy.delay(attempt, retry_after),
+            false => None,
+        };
+
+        match (delay, result) {
+            (Some(delay), _) => tokio::time::sleep(delay).await,
+            (None, Ok(response)) => break response,
+            (None, Err(err)) => return Err(err.into()),
+        }
+    };
+
+    let status = reqwest_response.status();
+    if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+        return Err(AuthError {
+            endpoint,
+            status: status.as_u16(),
+        }
+        .into());
+    }
+
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        anyhow::bail!("Rate limited by {endpoint}, try again later");
+    }
 
     reqwest_response
         .json()
@@ -44,6 +125,7 @@ pub async fn get_graphql<Q: GraphQLQuery>(
     let vars_ser = serde_json::to_string(&variables)?;
     let response = match post_graphql::<Q, _>(client, headers, endpoint, variables).await {
         Ok(response) => response,
+        Err(err) if err.is::<AuthError>() => return Err(err),
         Err(err) => {
             return Err(anyhow::anyhow!(
                 "Graphql failed with status:\n{err:?}\nvars: {vars_ser}",
@@ -57,8 +139,3 @@ pub async fn get_graphql<Q: GraphQLQuery>(
 
     response.data.context("get_graphql -> data")
 }
-
-// TODO: This is copied, should put this somewhere else
-pub(crate) fn make_bufname(remote: &Remote, oid: &OID, path: &str) -> String {
-    format!("sg://{}@{}/-/{}", remote.shortened(), oid.shortened(), path)
-}
@@ -11,7 +11,8 @@ pub(super) mod private {
     #[graphql(
         schema_path = "gql/schema.gql",
         query_path = "gql/list_files.gql",
-        response_derives = "Debug"
+        response_derives = "Debug",
+        variables_derives = "Clone"
     )]
     pub struct ListFilesQuery;
 }
@@ -19,13 +20,13 @@ pub(super) mod private {
 pub use private::{list_files_query::Variables, ListFilesQuery as Query};
 
 pub async fn request(
-    client: &reqwest::Client,
+    client: reqwest::Client,
     headers: reqwest::header::HeaderMap,
     endpoint: String,
     variables: Variables,
 ) -> Result<Vec<PathInfo>> {
     let remote = variables.name.clone();
-    let response = crate::get_graphql::<Query>(client, headers, endpoint, variables).await?;
+    let response = crate::get_graphql::<Query>(&client, headers, endpoint, variables).await?;
 
     let commit = response
         .repository
@@ -33,7 +34,7 @@ pub async fn request(
         .commit
         .context("No matching commit found")?;
 
-    let oid = commit.abbreviated_oid;
+    let oid = commit.oid;
     Ok(commit
         .tree
         .context("expected tree")?
@@ -11,7 +11,8 @@ pub(super) mod private {
     #[graphql(
         schema_path = "gql/schema.gql",
         query_path = "gql/path_info_query.gql",
-        response_derives = "Debug"
+        response_derives = "Debug",
+        variables_derives = "Clone"
     )]
     pub struct PathInfoQuery;
 }
@@ -19,20 +20,20 @@ pub(super) mod private {
 pub use private::{path_info_query::Variables, PathInfoQuery as Query};
 
 pub async fn request(
-    client: &reqwest::Client,
+    client: reqwest::Client,
     headers: reqwest::header::HeaderMap,
     endpoint: String,
     variables: Variables,
 ) -> Result<PathInfo> {
     use private::path_info_query::*;
-    let response = crate::get_graphql::<Query>(client, headers, endpoint, variables).await?;
+    let response = crate::get_graphql::<Query>(&client, headers, endpoint, variables).await?;
 
     let repository = response
         .repository
         .context("No matching repository found")?;
 
     let commit = repository.commit.context("No matching commit found")?;
-    let oid = commit.abbreviated_oid;
+    let oid = commit.oid;
 
     let gql_path = commit
         .path
@@ -11,24 +11,25 @@ pub(super) mod private {
     #[graphql(
         schema_path = "gql/schema.gql",
         query_path = "gql/references_query.gql",
-        response_derives = "Debug"
+        response_derives = "Debug",
+        variables_derives = "Clone"
     )]
     pub struct ReferencesQuery;
 }
 
 pub use private::{references_query::Variables, ReferencesQuery as Query};
 use {
-    crate::make_bufname,
     lsp_types::{Location, Position, Url},
+    sg_types::bufname::{make_bufname, BufnamePath},
 };
 
 pub async fn request(
-    client: &reqwest::Client,
+    client: reqwest::Client,
     headers: reqwest::header::HeaderMap,
     endpoint: String,
     variables: Variables,
 ) -> Result<Vec<Location>> {
-    let response = crate::get_graphql::<Query>(client, headers, endpoint, variables).await?;
+    let response = crate::get_graphql::<Query>(&client, headers, endpoint, variables).await?;
 
     let nodes = response
         .repository
@@ -58,7 +59,12 @@ pub async fn request(
         let path = node.resource.path;
 
         let location = Location {
-            uri: Url::parse(&make_bufname(&remote, &oid, &path))?,
+            uri: Url::parse(&make_bufname(
+                &remote,
+                &oid,
+                None,
+                Some(BufnamePath::Blob(&path)),
+            ))?,
             range: lsp_types::Range {
                 start: position,
                 end: position,
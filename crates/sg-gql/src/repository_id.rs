@@ -0,0 +1,34 @@
+use {
+    anyhow::{Context, Result},
+    graphql_client::GraphQLQuery,
+    sg_types::*,
+};
+
+pub(super) mod private {
+    use super::*;
+
+    #[derive(GraphQLQuery)]
+    #[graphql(
+        schema_path = "gql/schema.gql",
+        query_path = "gql/repository_id_query.gql",
+        response_derives = "Debug",
+        variables_derives = "Clone"
+    )]
+    pub struct RepositoryIdQuery;
+}
+
+pub use private::{repository_id_query::Variables, RepositoryIdQuery as Query};
+
+pub async fn request(
+    client: reqwest::Client,
+    headers: reqwest::header::HeaderMap,
+    endpoint: String,
+    variables: Variables,
+) -> Result<ID> {
+    let response = crate::get_graphql::<Query>(&client, headers, endpoint, variables).await?;
+
+    Ok(response
+        .repository
+        .context("No matching repository found")?
+        .id)
+}
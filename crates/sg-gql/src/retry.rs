@@ -0,0 +1,177 @@
+//! Retries and client-side limits for requests to Sourcegraph.
+//!
+//! Queries are retried on connection errors and on 429, 502, 503 and 504 responses, with
+//! exponential backoff and full jitter, or after the `Retry-After` the server asked for. Mutations
+//! are never retried. Each endpoint only gets `SRC_MAX_CONCURRENT_REQUESTS` (default 8) requests
+//! in flight at a time.
+
+use {
+    once_cell::sync::Lazy,
+    rand::Rng,
+    reqwest::{header::HeaderMap, Response, StatusCode},
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+        time::Duration,
+    },
+    tokio::sync::{broadcast, OwnedSemaphorePermit, Semaphore},
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+
+    /// Longest time to wait before a retry. If the server asks to wait longer than this, the
+    /// request fails instead.
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 4,
+            base_delay: Duration::from_millis(250),
+            max_delay: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait before retrying after `attempt` attempts, or `None` to give up.
+    pub fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+
+        match retry_after {
+            Some(retry_after) if retry_after > self.max_delay => None,
+            Some(retry_after) => Some(retry_after),
+            None => {
+                let ceiling = self
+                    .base_delay
+                    .saturating_mul(2u32.saturating_pow(attempt - 1))
+                    .min(self.max_delay);
+
+                Some(ceiling.mul_f64(rand::thread_rng().gen()))
+            }
+        }
+    }
+}
+
+pub fn is_retryable(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+/// Read `Retry-After`. Only the delay in seconds is supported, which is what Sourcegraph sends.
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    headers
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+static PERMITS: Lazy<Mutex<HashMap<String, Arc<Semaphore>>>> = Lazy::new(Default::default);
+
+/// Wait until another request can be sent to `endpoint`.
+pub async fn acquire(endpoint: &str) -> OwnedSemaphorePermit {
+    let semaphore = PERMITS
+        .lock()
+        .expect("to unlock permits")
+        .entry(endpoint.to_string())
+        .or_insert_with(|| {
+            let limit = std::env::var("SRC_MAX_CONCURRENT_REQUESTS")
+                .ok()
+                .and_then(|limit| limit.parse().ok())
+                .filter(|limit| *limit > 0)
+                .unwrap_or(8);
+
+            Arc::new(Semaphore::new(limit))
+        })
+        .clone();
+
+    semaphore
+        .acquire_owned()
+        .await
+        .expect("semaphore is never closed")
+}
+
+/// The last known rate-limit state of an endpoint.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct RateLimit {
+    pub endpoint: String,
+
+    /// Whether the last response was a 429.
+    pub limited: bool,
+
+    /// Seconds the server asked to wait, when limited.
+    pub retry_after: Option<u64>,
+
+    pub limit: Option<u64>,
+    pub remaining: Option<u64>,
+}
+
+static RATE_LIMITS: Lazy<Mutex<HashMap<String, RateLimit>>> = Lazy::new(Default::default);
+static CHANGES: Lazy<broadcast::Sender<RateLimit>> = Lazy::new(|| broadcast::channel(16).0);
+
+/// Receive the rate-limit state of an endpoint whenever it starts or stops being limited.
+pub fn subscribe() -> broadcast::Receiver<RateLimit> {
+    CHANGES.subscribe()
+}
+
+pub fn rate_limits() -> Vec<RateLimit> {
+    let mut limits = RATE_LIMITS
+        .lock()
+        .expect("to unlock rate limits")
+        .values()
+        .cloned()
+        .collect::<Vec<_>>();
+
+    limits.sort_by(|a, b| a.endpoint.cmp(&b.endpoint));
+    limits
+}
+
+/// Update the rate-limit state of `endpoint` from a response.
+pub fn record(endpoint: &str, response: &Response) {
+    let header = |name: &str| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse().ok())
+    };
+
+    let limited = response.status() == StatusCode::TOO_MANY_REQUESTS;
+    let state = RateLimit {
+        endpoint: endpoint.to_string(),
+        limited,
+        retry_after: match limited {
+            true => retry_after(response.headers()).map(|delay| delay.as_secs()),
+            false => None,
+        },
+        limit: header("X-RateLimit-Limit"),
+        remaining: header("X-RateLimit-Remaining"),
+    };
+
+    let previous = RATE_LIMITS
+        .lock()
+        .expect("to unlock rate limits")
+        .insert(endpoint.to_string(), state.clone());
+
+    let was_limited = matches!(previous, Some(RateLimit { limited: true, .. }));
+    if was_limited != limited {
+        // No receivers just means that nobody is listening for changes
+        let _ = CHANGES.send(state);
+    }
+}
@@ -10,7 +10,8 @@ pub(super) mod private {
     #[graphql(
         schema_path = "gql/schema.gql",
         query_path = "gql/search.gql",
-        response_derives = "Debug"
+        response_derives = "Debug",
+        variables_derives = "Clone"
     )]
     pub struct SearchQuery;
 }
@@ -19,13 +20,13 @@ pub use private::{search_query::Variables, SearchQuery as Query};
 use sg_types::SearchResult;
 
 pub async fn request(
-    client: &reqwest::Client,
+    client: reqwest::Client,
     headers: reqwest::header::HeaderMap,
     endpoint: String,
     variables: Variables,
 ) -> Result<Vec<SearchResult>> {
     use private::search_query::SearchQuerySearchResultsResults::*;
-    let response = crate::get_graphql::<Query>(client, headers, endpoint, variables).await?;
+    let response = crate::get_graphql::<Query>(&client, headers, endpoint, variables).await?;
 
     let results = response.search.context("search")?.results.results;
 
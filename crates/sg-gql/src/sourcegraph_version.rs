@@ -7,7 +7,8 @@ pub(super) mod private {
     #[graphql(
         schema_path = "gql/schema.gql",
         query_path = "gql/version_query.gql",
-        response_derives = "Debug"
+        response_derives = "Debug",
+        variables_derives = "Clone"
     )]
     pub struct VersionQuery;
 }
@@ -15,12 +16,12 @@ pub(super) mod private {
 pub use private::{version_query::Variables, VersionQuery as Query};
 
 pub async fn request(
-    client: &reqwest::Client,
+    client: reqwest::Client,
     headers: reqwest::header::HeaderMap,
     endpoint: String,
     variables: Variables,
 ) -> Result<SourcegraphVersion> {
-    crate::get_graphql::<Query>(client, headers, endpoint, variables)
+    crate::get_graphql::<Query>(&client, headers, endpoint, variables)
         .await
         .map(|response_body| {
             let version = response_body.site;
@@ -0,0 +1,236 @@
+//! The `sg://` bufname scheme, shared by the nvim agent and the LSP server.
+//!
+//! The scheme can be configured by setting `SG_BUFNAME_CONFIG` to a JSON encoded
+//! [`BufnameConfig`], which is how the editor passes its configuration to both processes.
+
+use {
+    crate::{encoding, Remote, OID},
+    serde::{Deserialize, Serialize},
+    std::{collections::HashMap, sync::OnceLock},
+};
+
+/// Aliases that are always available. Aliases from the config are added to these, and take
+/// precedence over them.
+const DEFAULT_HOST_ALIASES: &[(&str, &str)] = &[("gh", "github.com"), ("gl", "gitlab.com")];
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BufnameConfig {
+    /// How many characters of a commit SHA to display. `None` displays the full SHA, which is
+    /// the only way to guarantee that bufnames for different commits never collide.
+    pub oid_length: Option<usize>,
+
+    /// Short names for hosts, e.g. `gh` for `github.com`, in addition to the default aliases.
+    /// Aliases are used when generating bufnames and expanded again when parsing them.
+    pub host_aliases: HashMap<String, String>,
+
+    /// Display the revision a file was opened at (e.g. a branch name) instead of the commit.
+    pub show_branch: bool,
+}
+
+/// A file or directory in a bufname. The kind is always written, so that a path starting with
+/// `blob/`, `tree/` or `raw/` isn't read back as a kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BufnamePath<'a> {
+    Blob(&'a str),
+    Tree(&'a str),
+}
+
+impl BufnameConfig {
+    /// The host an alias stands for, from the config or the defaults.
+    fn alias_host(&self, alias: &str) -> Option<&str> {
+        match self.host_aliases.get(alias) {
+            Some(host) => Some(host),
+            None => DEFAULT_HOST_ALIASES
+                .iter()
+                .find(|(default, _)| *default == alias)
+                .map(|(_, host)| *host),
+        }
+    }
+
+    /// The alias for a host. Configured aliases are preferred, and the shortest one is used if a
+    /// host has several, so bufnames don't depend on the order of a map.
+    fn host_alias(&self, host: &str) -> Option<&str> {
+        let configured = self
+            .host_aliases
+            .iter()
+            .filter(|(_, h)| *h == host)
+            .map(|(alias, _)| alias.as_str())
+            .min_by_key(|alias| (alias.len(), *alias));
+
+        configured.or_else(|| {
+            DEFAULT_HOST_ALIASES
+                .iter()
+                .find(|(alias, h)| *h == host && !self.host_aliases.contains_key(*alias))
+                .map(|(alias, _)| *alias)
+        })
+    }
+
+    /// Replace the host of a remote with its alias, if it has one.
+    pub fn shorten_remote(&self, remote: &Remote) -> String {
+        let (host, rest) = remote.0.split_once('/').unwrap_or((&remote.0, ""));
+        match self.host_alias(host) {
+            Some(alias) if rest.is_empty() => alias.to_string(),
+            Some(alias) => format!("{alias}/{rest}"),
+            None => remote.0.to_string(),
+        }
+    }
+
+    /// Replace a leading host alias with the host it stands for.
+    pub fn expand_remote(&self, remote: &str) -> String {
+        let (alias, rest) = remote.split_once('/').unwrap_or((remote, ""));
+        match self.alias_host(alias) {
+            Some(host) if rest.is_empty() => host.to_string(),
+            Some(host) => format!("{host}/{rest}"),
+            None => remote.to_string(),
+        }
+    }
+
+    /// The revision to display for an entry. Commit SHAs are truncated to `oid_length`, other
+    /// revisions (branches, tags, `HEAD`) are never truncated.
+    pub fn display_revision(&self, oid: &OID, revision: Option<&str>) -> String {
+        if let (true, Some(revision)) = (self.show_branch, revision) {
+            return revision.to_string();
+        }
+
+        match self.oid_length {
+            Some(length) if oid.is_commit_sha() && oid.0.len() > length => {
+                oid.0[..length].to_string()
+            }
+            _ => oid.0.to_string(),
+        }
+    }
+
+    pub fn bufname(
+        &self,
+        remote: &Remote,
+        oid: &OID,
+        revision: Option<&str>,
+        path: Option<BufnamePath>,
+    ) -> String {
+        let remote = self.shorten_remote(remote);
+        let revision = encoding::encode_revision(&self.display_revision(oid, revision));
+
+        match path {
+            Some(path) => {
+                let (kind, path) = match path {
+                    BufnamePath::Blob(path) => ("blob", path),
+                    BufnamePath::Tree(path) => ("tree", path),
+                };
+
+                format!(
+                    "sg://{remote}@{revision}/-/{kind}/{}",
+                    encoding::encode_path(path)
+                )
+            }
+            None => format!("sg://{remote}@{revision}"),
+        }
+    }
+}
+
+/// The configuration read from `SG_BUFNAME_CONFIG`, or the default if it is unset or invalid.
+pub fn config() -> &'static BufnameConfig {
+    static CONFIG: OnceLock<BufnameConfig> = OnceLock::new();
+    CONFIG.get_or_init(|| {
+        std::env::var("SG_BUFNAME_CONFIG")
+            .ok()
+            .and_then(|config| serde_json::from_str(&config).ok())
+            .unwrap_or_default()
+    })
+}
+
+/// Generate a bufname using the configured scheme.
+pub fn make_bufname(
+    remote: &Remote,
+    oid: &OID,
+    revision: Option<&str>,
+    path: Option<BufnamePath>,
+) -> String {
+    config().bufname(remote, oid, revision, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    fn remote(remote: &str) -> Remote {
+        remote.to_string().into()
+    }
+
+    #[test]
+    fn revision_is_truncated_or_replaced_by_the_branch() {
+        let oid: OID = SHA.to_string().into();
+
+        let config = BufnameConfig::default();
+        assert_eq!(
+            config.bufname(
+                &remote("gitlab.com/x/y"),
+                &oid,
+                Some("main"),
+                Some(BufnamePath::Blob("a.rs"))
+            ),
+            format!("sg://gl/x/y@{SHA}/-/blob/a.rs")
+        );
+
+        let config = BufnameConfig {
+            oid_length: Some(7),
+            ..Default::default()
+        };
+        assert_eq!(
+            config.bufname(&remote("gitlab.com/x/y"), &oid, Some("main"), None),
+            "sg://gl/x/y@0123456"
+        );
+
+        // Branch names are never truncated, even when commits are, including ones that look
+        // like a short SHA.
+        for branch in ["feature/long-name", "deadbeef"] {
+            let branch: OID = branch.to_string().into();
+            assert_eq!(
+                config.bufname(&remote("gitlab.com/x/y"), &branch, None, None),
+                format!("sg://gl/x/y@{}", branch.0)
+            );
+        }
+
+        let config = BufnameConfig {
+            show_branch: true,
+            ..config
+        };
+        assert_eq!(
+            config.bufname(
+                &remote("gitlab.com/x/y"),
+                &oid,
+                Some("main"),
+                Some(BufnamePath::Tree("src"))
+            ),
+            "sg://gl/x/y@main/-/tree/src"
+        );
+    }
+
+    #[test]
+    fn configured_aliases_are_added_to_the_defaults() {
+        let config: BufnameConfig =
+            serde_json::from_str(r#"{ "host_aliases": { "ghe": "ghe.corp.com" } }"#).unwrap();
+
+        assert_eq!(config.expand_remote("gh/x/y"), "github.com/x/y");
+        assert_eq!(config.expand_remote("ghe/x/y"), "ghe.corp.com/x/y");
+        assert_eq!(config.shorten_remote(&remote("github.com/x/y")), "gh/x/y");
+        assert_eq!(
+            config.shorten_remote(&remote("ghe.corp.com/x/y")),
+            "ghe/x/y"
+        );
+    }
+
+    #[test]
+    fn configured_aliases_take_precedence() {
+        let config: BufnameConfig = serde_json::from_str(
+            r#"{ "host_aliases": { "gh": "ghe.corp.com", "hub": "github.com" } }"#,
+        )
+        .unwrap();
+
+        assert_eq!(config.expand_remote("gh/x/y"), "ghe.corp.com/x/y");
+        assert_eq!(config.shorten_remote(&remote("github.com/x/y")), "hub/x/y");
+        assert_eq!(config.expand_remote("gl/x/y"), "gitlab.com/x/y");
+    }
+}
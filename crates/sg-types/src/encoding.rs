@@ -0,0 +1,57 @@
+//! Percent-encoding for the revision and path components of `sg://` bufnames.
+//!
+//! Git allows revisions and paths to contain characters that have a special meaning in a URI,
+//! such as `?` and `#`, so they are encoded when generating a bufname and decoded when parsing.
+
+use {
+    percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS},
+    std::str::Utf8Error,
+};
+
+/// Characters that would otherwise be read as the start of a query or fragment, plus `%` itself
+/// so that encoding is reversible and whitespace so that bufnames can be passed to `:edit`.
+const COMPONENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'?')
+    .add(b'<')
+    .add(b'>')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// Characters that aren't allowed in a URL path segment either, for links opened in a browser.
+const URL_SEGMENT: &AsciiSet = &COMPONENT.add(b'[').add(b']').add(b'^').add(b'|').add(b'\\');
+
+pub fn encode_path(path: &str) -> String {
+    utf8_percent_encode(path, COMPONENT).to_string()
+}
+
+/// Encode a revision. Revisions may contain slashes, but a `-` segment would be mistaken for
+/// the `/-/` separator, so it is encoded as well.
+pub fn encode_revision(revision: &str) -> String {
+    revision
+        .split('/')
+        .map(|segment| match segment {
+            "-" => "%2D".to_string(),
+            segment => utf8_percent_encode(segment, COMPONENT).to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Encode a path for a URL, a segment at a time so that the slashes between them are kept.
+pub fn encode_url_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| utf8_percent_encode(segment, URL_SEGMENT).to_string())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+pub fn decode(component: &str) -> Result<String, Utf8Error> {
+    percent_decode_str(component)
+        .decode_utf8()
+        .map(|decoded| decoded.into_owned())
+}
@@ -4,6 +4,9 @@ use {
     std::str::FromStr,
 };
 
+pub mod bufname;
+pub mod encoding;
+
 pub type ID = String;
 pub type GitObjectID = String;
 
@@ -25,18 +28,56 @@ pub enum Embedding {
     },
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum CodySpeaker {
     Human,
     Assistant,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CodyMessage {
     pub speaker: CodySpeaker,
     pub text: String,
 }
 
+/// How a Cody completion is generated. Anything not set uses the default for the instance.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CompletionParameters {
+    /// A model the instance allows, see [`CodyModel`].
+    pub model: Option<String>,
+    pub temperature: Option<f64>,
+    pub max_tokens: Option<i64>,
+
+    /// Text that ends the completion when it is generated. It isn't included in the completion.
+    #[serde(default)]
+    pub stop_sequences: Vec<String>,
+
+    pub top_k: Option<i64>,
+
+    /// Only sample from the most likely tokens whose probabilities add up to this, like `0.9`.
+    pub top_p: Option<f64>,
+}
+
+/// A model that Cody can use on an instance.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CodyModel {
+    pub id: String,
+    pub provider: String,
+    pub purpose: ModelPurpose,
+
+    /// Tokens of prompt and completion the model accepts, when the instance sets a limit.
+    pub max_tokens: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModelPurpose {
+    Chat,
+    FastChat,
+    Completion,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct PathInfo {
     pub remote: String,
@@ -54,16 +95,6 @@ pub struct SourcegraphVersion {
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Remote(pub String);
 
-impl Remote {
-    pub fn shortened(&self) -> String {
-        if self.0 == "github.com" {
-            "gh".to_string()
-        } else {
-            self.0.to_owned()
-        }
-    }
-}
-
 impl From<String> for Remote {
     fn from(value: String) -> Self {
         Self(value)
@@ -82,12 +113,10 @@ impl FromStr for Remote {
 pub struct OID(pub String);
 
 impl OID {
-    pub fn shortened(&self) -> String {
-        if self.0.len() < 5 {
-            self.0.to_string()
-        } else {
-            self.0[..5].to_string()
-        }
+    /// Whether this is a full commit SHA, rather than a branch or tag name. Abbreviated SHAs
+    /// can't be told apart from branches like `deadbeef`, so they don't count.
+    pub fn is_commit_sha(&self) -> bool {
+        self.0.len() == 40 && self.0.chars().all(|c| c.is_ascii_hexdigit())
     }
 }
 
@@ -113,6 +142,22 @@ pub struct SearchResult {
     pub line: usize,
 }
 
+/// The kind of code host that a repository is mirrored from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CodeHost {
+    GitHub,
+    GitLab,
+    BitbucketServer,
+    BitbucketCloud,
+    Other,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExternalLink {
+    pub url: String,
+    pub host: CodeHost,
+}
+
 pub type RecipeID = String;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
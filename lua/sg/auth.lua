@@ -18,6 +18,18 @@
 ---
 --- Otherwise use |:SourcegraphLogin| to set up authentication.
 ---
+--- For short-lived tokens, set SRC_ACCESS_TOKEN_COMMAND to a command that
+--- prints either the token or JSON like `{ "token": "...", "expires_in": 3600 }`
+--- (or `"expires_at"` as a unix timestamp). The token is cached until it
+--- expires or is rejected by the server, and then the command is run again.
+---
+--- Saved credentials go to the OS keyring, or to an encrypted file under
+--- your config directory when no keyring is available. Set
+--- SG_CREDENTIAL_STORE to "keyring", "file", "command" or "env" to choose.
+--- The "command" store reads the token (or saved JSON) from the output of
+--- SG_CREDENTIAL_COMMAND (e.g. `pass show sourcegraph`) and saves by piping
+--- to SG_CREDENTIAL_STORE_COMMAND. The "env" store never saves anything.
+---
 --- NOTE: Cody-App support has been removed (for now).
 --- We're currently exploring what App will look like in the future,
 --- but it will not currently be supported anymore.
@@ -68,7 +80,7 @@ M.set = function(new_endpoint, new_token, opts)
   endpoint = vim.env.SRC_ENDPOINT
   token = vim.env.SRC_ACCESS_TOKEN
 
-  if not opts.initialize then
+  if not opts.initialize and not opts.saved then
     -- Notify nvim-agent that the configuration has changed
     require("sg.rpc").get_auth({ endpoint = new_endpoint, token = new_token }, function()
       -- Notify Cody that configuration has changed
@@ -89,6 +101,16 @@ M.set = function(new_endpoint, new_token, opts)
   end)
 end
 
+--- Forget the current token, e.g. after logging out. The nvim-agent already
+--- knows, so only Cody needs to be told.
+M.clear = function()
+  vim.env.SRC_ACCESS_TOKEN = nil
+  token = nil
+  M._is_pro = nil
+
+  require("sg.cody.rpc").config_did_change()
+end
+
 M.is_pro = function()
   if M._is_pro == nil then
     -- TODO: Probably want to check a bit more than that.
@@ -239,6 +239,15 @@ M._open_remote_file = function(bufnr, bufname, data, callback)
         vim.api.nvim_buf_set_lines(bufnr, 0, -1, false, contents)
       end)
 
+      -- Positions are 1-indexed, but nvim_win_set_cursor expects a 0-indexed column. The
+      -- buffer may have been loaded in a window other than the current one (or in none).
+      local position = data.position
+      local winid = vim.fn.bufwinid(bufnr)
+      if position and position.line and winid ~= -1 then
+        local col = math.max((position.col or 1) - 1, 0)
+        pcall(vim.api.nvim_win_set_cursor, winid, { position.line, col })
+      end
+
       vim.api.nvim_exec_autocmds("BufRead", {})
       vim.bo[bufnr].filetype = vim.filetype.match { filename = data.path, contents = contents }
         or filetype.detect(data.path, {})
@@ -26,10 +26,27 @@
 ---@field cody_agent string?: path to the cody-agent js bundle
 ---@field on_attach function?: function to run when attaching to sg://<file> buffers
 ---@field src_headers? table<string, string>: Headers to be sent with each sg request
+---@field bufname sg.config.bufname?: How to display sg:// buffer names
+---@field network sg.config.network?: Proxy and TLS settings for requests to Sourcegraph
+---@field request_timeout number?: Seconds before a request to sg-nvim-agent is abandoned, or 0 to never abandon them. Default: 60
 
 ---@class sg.config.chat
 ---@field default_model? string: The name of the default model to use
 
+---@class sg.config.bufname
+---@field oid_length? number: Number of characters of commit SHAs to display. Default: full SHA
+---@field host_aliases? table<string, string>: Short names for hosts, added to (and overriding) the defaults: { gh = "github.com", gl = "gitlab.com" }
+---@field show_branch? boolean: Display the branch a file was opened at, instead of the commit
+
+---@class sg.config.network
+---@field proxy? string: Proxy for all requests. Default: $SRC_PROXY or $HTTPS_PROXY
+---@field no_proxy? string: Hosts that bypass the proxy. Default: $NO_PROXY
+---@field ca_files? string[]: PEM files with extra root certificates. Default: $SRC_CA_FILES
+---@field ca_only? boolean: Only trust `ca_files`, not the system root certificates
+---@field client_cert? string: Client certificate (PEM, or PKCS#12) for mTLS. Default: $SRC_CLIENT_CERT
+---@field client_key? string: PKCS#8 key (PEM) for `client_cert`. Default: $SRC_CLIENT_KEY
+---@field client_cert_password? string: Password for a PKCS#12 `client_cert`
+
 ---@type sg.config
 local config = {
   enable_cody = true,
@@ -39,6 +56,11 @@ local config = {
     default_model = nil,
   },
 
+  bufname = {},
+
+  network = {},
+  request_timeout = 60,
+
   download_binaries = true,
   node_executable = "node",
   skip_node_check = false,
@@ -7,6 +7,7 @@ local finders = require "telescope.finders"
 local entry_display = require "telescope.pickers.entry_display"
 
 local rpc = require "sg.rpc"
+local utils = require "sg.utils"
 
 local telescope = {}
 
@@ -121,7 +122,7 @@ telescope.fuzzy_search_results = function(opts)
                 value = entry,
                 ordinal = string.format("%s %s", entry.file, entry.preview),
                 display = display,
-                filename = string.format("sg://%s/-/%s", entry.repo, entry.file),
+                filename = string.format("sg://%s/-/blob/%s", entry.repo, utils.encode_path(entry.file)),
                 row = entry.line + 1,
               }
             end,
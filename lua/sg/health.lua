@@ -110,6 +110,25 @@ local report_env = function()
     ok = false
   else
     vim.health.ok("  Sourcegraph Connection info: " .. vim.inspect(info))
+
+    if info.sourcegraph_version_error then
+      vim.health.error("  Unable to reach Sourcegraph: " .. info.sourcegraph_version_error)
+      ok = false
+    end
+
+    if info.credential_error then
+      vim.health.warn(
+        string.format(
+          "  Unable to load saved credentials (%s store): %s",
+          info.credential_store.kind,
+          info.credential_error
+        )
+      )
+    else
+      vim.health.ok(
+        string.format("  Credentials are saved in the %s store", info.credential_store.kind)
+      )
+    end
   end
 
   return ok
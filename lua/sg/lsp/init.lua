@@ -48,6 +48,11 @@ M.get_client_id = function()
     src_headers = nil
   end
 
+  local bufname = require("sg.config").bufname
+  if not bufname or vim.tbl_isempty(bufname) then
+    bufname = nil
+  end
+
   ---@diagnostic disable-next-line: missing-fields
   local headers = require("sg.config").src_headers
   M._client = vim.lsp.start_client {
@@ -57,6 +62,7 @@ M.get_client_id = function()
       SRC_ENDPOINT = auth.endpoint,
       SRC_ACCESS_TOKEN = auth.token,
       SRC_HEADERS = src_headers and vim.json.encode(src_headers) or nil,
+      SG_BUFNAME_CONFIG = bufname and vim.json.encode(bufname) or nil,
     },
     handlers = {
       -- For definitions, we need to preload the buffers so that we don't
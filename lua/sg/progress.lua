@@ -0,0 +1,66 @@
+---@brief [[
+--- Progress of long running operations in sg-nvim-agent, such as searches,
+--- fetching files and browser logins.
+---
+--- Every update fires a `User SgProgress` autocmd with the operation as `data`,
+--- so statusline or progress plugins can display it. |sg.progress.status|
+--- returns a short summary that can be used directly in a statusline.
+---@brief ]]
+
+local M = {}
+
+---@class sg.progress.Operation
+---@field token string
+---@field title string
+---@field message string?
+---@field percentage number?
+---@field done boolean
+
+--- Operations that have not ended yet, by token
+---@type table<string, sg.progress.Operation>
+M.active = {}
+
+--- Update progress from a `$/progress` notification
+---@param data { token: string, value: table }
+M.update = function(data)
+  local value = data.value
+  local operation = M.active[data.token]
+
+  if value.kind == "begin" then
+    operation = { token = data.token, title = value.title, done = false }
+    M.active[data.token] = operation
+  end
+
+  if not operation then
+    return
+  end
+
+  operation.message = value.message or operation.message
+  operation.percentage = value.percentage or operation.percentage
+
+  if value.kind == "end" then
+    operation.done = true
+    M.active[data.token] = nil
+  end
+
+  vim.api.nvim_exec_autocmds("User", { pattern = "SgProgress", data = operation })
+end
+
+--- Summary of the running operations, for a statusline
+---@return string
+M.status = function()
+  local parts = {}
+  for _, operation in pairs(M.active) do
+    local part = operation.title
+    if operation.percentage then
+      part = string.format("%s (%d%%)", part, operation.percentage)
+    end
+
+    table.insert(parts, part)
+  end
+
+  table.sort(parts)
+  return table.concat(parts, ", ")
+end
+
+return M
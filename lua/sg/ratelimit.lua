@@ -23,8 +23,24 @@ M.state = {
 
   -- Whether the user has been notified of rate limiting yet this session
   has_notified_user = {},
+
+  -- Last known rate limit state of each endpoint, sent by sg-nvim-agent
+  endpoints = {},
 }
 
+--- Update the rate limit state of an endpoint
+---@param data { endpoint: string, limited: boolean, retry_after: number?, limit: number?, remaining: number? }
+M.update = function(data)
+  M.state.endpoints[data.endpoint] = data
+
+  if data.limited then
+    M.state.has_been_ratelimited = true
+
+    local retry = data.retry_after and string.format(", retrying in %ss", data.retry_after) or ""
+    vim.notify(string.format("[sg] Rate limited by %s%s", data.endpoint, retry))
+  end
+end
+
 M.is_ratelimit_err = function(err)
   local has_been_ratelimited = err and err.code == -32000
   if has_been_ratelimited then
@@ -5,16 +5,60 @@ local bin_sg_nvim = require("sg.config").get_nvim_agent()
 
 local M = {}
 
+--- Handlers for streamed completions, by request id
+---@type table<number, fun(delta: string)>
+M.streams = {}
+
 local notification_handlers = {
-  ["initialize"] = function(data)
-    if data.endpoint and data.token then
-      require("sg.auth").set(data.endpoint, data.token, { initialize = true })
-    end
+  ["initialize"] = function(_)
+    -- The token isn't sent with this, `M.start` asks for it with "sourcegraph/auth"
   end,
 
   ["display_text"] = function(data)
     print("display_text::", vim.inspect(data))
   end,
+
+  ["credentials_changed"] = function(data)
+    if data.token == nil or data.token == vim.NIL then
+      -- Logged out (or switched to a profile without a token)
+      require("sg.auth").clear()
+    elseif data.endpoint then
+      require("sg.auth").set(data.endpoint, data.token, { saved = true })
+    end
+  end,
+
+  ["login"] = function(data)
+    if data.error then
+      vim.notify(string.format("[cody] Login failed: %s", data.error))
+    else
+      vim.notify(string.format("[cody] Logged in to %s", data.endpoint))
+    end
+  end,
+
+  ["rate_limit"] = function(data)
+    require("sg.ratelimit").update(data)
+  end,
+
+  ["UpdateChat"] = function(data)
+    local on_delta = M.streams[data.id]
+    if on_delta then
+      on_delta(data.message)
+    end
+  end,
+
+  ["$/progress"] = function(data)
+    require("sg.progress").update(data)
+  end,
+
+  ["log"] = function(data)
+    local write = log[data.level] or log.info
+    write("[sg-agent]", data.message)
+  end,
+
+  ["auth_invalid"] = function(data)
+    log.warn("auth invalid", data)
+    require("sg.notify").INVALID_AUTH()
+  end,
 }
 
 local server_handlers = {}
@@ -49,6 +93,12 @@ M.start = function(opts)
     SRC_HEADERS = vim.json.encode(src_headers)
   end
 
+  local bufname = require("sg.config").bufname
+  local SG_BUFNAME_CONFIG
+  if bufname and not vim.tbl_isempty(bufname) then
+    SG_BUFNAME_CONFIG = vim.json.encode(bufname)
+  end
+
   -- Verify that the environment is properly configured
   M.client = rpc_start({ bin_sg_nvim }, {
     notification = function(method, data)
@@ -75,6 +125,7 @@ M.start = function(opts)
       SRC_ACCESS_TOKEN = vim.env.SRC_ACCESS_TOKEN,
       SRC_ENDPOINT = vim.env.SRC_ENDPOINT,
       SRC_HEADERS = SRC_HEADERS,
+      SG_BUFNAME_CONFIG = SG_BUFNAME_CONFIG,
     },
   })
 
@@ -83,6 +134,26 @@ M.start = function(opts)
     return nil
   end
 
+  -- Send editor settings before anything else, so they apply to every request
+  local config = require "sg.config"
+  M.request("initialize", {
+    network = not vim.tbl_isempty(config.network or {}) and config.network or nil,
+    request_timeout = config.request_timeout,
+  }, function(err)
+    if err then
+      vim.notify(string.format("[sg] invalid settings: %s", vim.inspect(err)))
+    end
+  end)
+
+  -- Check every request against the agent's schema while testing
+  if config.testing then
+    M.request("$/schema", {}, function(err, schema)
+      if not err then
+        M.schema = schema
+      end
+    end)
+  end
+
   -- Schedule getting the auth from neovim, if possible.
   M.request("sourcegraph/auth", {}, function(err, data)
     if err then
@@ -106,12 +177,25 @@ M.notify = function(...)
   return client.notify(...)
 end
 
+--- Abort a request that was sent with |M.request|
+---@param id number: The request id, the second value returned by |M.request| (`local ok, id = M.request(...)`)
+M.cancel = function(id)
+  return M.notify("$/cancel", { id = id })
+end
+
 M.request = function(method, params, callback)
   local client = M.start()
   if not client then
     return callback("no available client", nil)
   end
 
+  if M.schema then
+    local problems = require("sg.schema").check(M.schema, method, params)
+    if not vim.tbl_isempty(problems) then
+      log.error("[sg-agent] invalid request:", method, problems)
+    end
+  end
+
   return client.request(method, params, function(err, result)
     return callback(err, result)
   end)
@@ -52,11 +52,12 @@ end
 
 --- Get info about current sourcegraph info
 ---@param path string
----@param position { start_line: number, start_col: number, end_line: number, end_col: number }
+---@param position { start_line: number, start_col: number, end_line: number, end_col: number, ranges: table[]? }
 ---@param callback any
-function rpc.get_link(path, position, callback)
+---@param opts { permalink: boolean?, code_host: boolean? }?
+function rpc.get_link(path, position, callback, opts)
   ---@type table
-  local args = vim.deepcopy(position)
+  local args = vim.tbl_extend("force", vim.deepcopy(position), opts or {})
   args.path = path
 
   req("sourcegraph/link", args, callback)
@@ -70,12 +71,218 @@ function rpc.get_auth(creds, callback)
   req("sourcegraph/auth", creds or {}, callback)
 end
 
+--- List the saved Sourcegraph profiles
+---
+--- Any request can be sent with a specific profile by adding `profile = "name"`
+--- to its parameters, otherwise the active profile is used.
+---@param callback fun(err: string?, profiles: { name: string, endpoint: string?, token_set: boolean, active: boolean, hosts: string[] }[]?)
+function rpc.list_profiles(callback)
+  req("sourcegraph/profiles/list", {}, callback)
+end
+
+--- Make a saved profile the active profile
+---@param name string
+---@param callback function
+function rpc.switch_profile(name, callback)
+  req("sourcegraph/profiles/switch", { name = name }, callback)
+end
+
+--- Remove a saved profile
+---@param name string
+---@param callback function
+function rpc.remove_profile(name, callback)
+  req("sourcegraph/profiles/remove", { name = name }, callback)
+end
+
+--- Send requests for repositories on a host (e.g. "ghe.corp.com") to a profile
+---@param host string
+---@param name string?: The profile to use, or nil to remove the route
+---@param callback function
+function rpc.route_host(host, name, callback)
+  req("sourcegraph/profiles/route", { host = host, route = name }, callback)
+end
+
 function rpc.get_user_info(callback)
   req("sourcegraph/get_user_info", { testing = false }, callback)
 end
 
-function rpc.dotcom_login(port, callback)
-  req("sourcegraph/dotcom_login", { port = port }, callback)
+--- Start a browser login. The callback receives the url to open, and the result
+--- of the login is sent later as a "login" notification. Starting another login
+--- replaces one that is still waiting for the browser.
+---@param endpoint string
+---@param port number
+---@param callback fun(err: string?, data: { url: string }?)
+---@return number?: The request id, to cancel the login with |sg.request.cancel|
+function rpc.login(endpoint, port, callback)
+  local _, id = req("sourcegraph/login", { endpoint = endpoint, port = port }, callback)
+  return id
+end
+
+--- Stream a Cody completion. `on_delta` is called with each new piece of text,
+--- and the callback with the whole completion and its usage once it is done.
+---@param message string
+---@param opts { prefix: string?, temperature: number?, model: string?, max_tokens: number?, stop_sequences: string[]?, top_k: number?, top_p: number?, context: table? }?: See |rpc.models| and |rpc.buffer_context|
+---@param on_delta fun(delta: string)
+---@param callback fun(err: table?, data: { completion: string, citations: table[]?, usage: table }?)
+---@return number?: The request id, to cancel the completion with |sg.request.cancel|
+function rpc.complete_stream(message, opts, on_delta, callback)
+  opts = opts or {}
+
+  local request = require "sg.request"
+  local id
+  local _, request_id = request.request("Complete", {
+    message = message,
+    prefix = opts.prefix,
+    temperature = opts.temperature,
+    model = opts.model,
+    max_tokens = opts.max_tokens,
+    stop_sequences = opts.stop_sequences,
+    top_k = opts.top_k,
+    top_p = opts.top_p,
+    stream = true,
+    context = opts.context,
+  }, function(err, data)
+    -- The callback can run before the id is known, if the request fails to send
+    if id then
+      request.streams[id] = nil
+    end
+    callback(err, data)
+  end)
+
+  id = request_id
+  if id then
+    request.streams[id] = on_delta
+  end
+
+  return id
+end
+
+--- List the models Cody can use on the instance
+---@param callback fun(err: table?, models: { id: string, provider: string, purpose: "chat"|"fast_chat"|"completion", max_tokens: number? }[]?)
+function rpc.models(callback)
+  req("cody/models", {}, callback)
+end
+
+--- Request inline completions at the cursor in the current window. Each
+--- candidate replaces its range (0-indexed lines, byte columns) with its text.
+--- One candidate is requested, with the instance's completion model, unless
+--- `opts` asks for more (up to 3) or for another model (see |rpc.models|).
+---@param opts { candidates: number?, model: string? }?
+---@param callback fun(err: table?, candidates: { text: string, range: { start: { line: number, character: number }, ["end"]: { line: number, character: number } } }[]?)
+---@return number?: The request id, to cancel the request with |sg.request.cancel|
+function rpc.autocomplete(opts, callback)
+  opts = opts or {}
+
+  local bufnr = vim.api.nvim_get_current_buf()
+  local row, col = unpack(vim.api.nvim_win_get_cursor(0))
+  local lines = vim.api.nvim_buf_get_lines(bufnr, 0, -1, false)
+
+  local before = vim.list_slice(lines, 1, row)
+  before[row] = string.sub(before[row] or "", 1, col)
+  local after = vim.list_slice(lines, row)
+  after[1] = string.sub(after[1] or "", col + 1)
+
+  local _, request_id = req("cody/autocomplete", {
+    prefix = table.concat(before, "\n"),
+    suffix = table.concat(after, "\n"),
+    path = vim.api.nvim_buf_get_name(bufnr),
+    language = vim.bo[bufnr].filetype ~= "" and vim.bo[bufnr].filetype or nil,
+    candidates = opts.candidates,
+    model = opts.model,
+  }, callback)
+
+  return request_id
+end
+
+--- Context options for a question asked from `bufnr`: the buffer's file and,
+--- when given, the selected lines (1-based, inclusive, like |getpos()|).
+---@param bufnr number
+---@param selection { start: number, finish: number }?
+---@return table
+function rpc.buffer_context(bufnr, selection)
+  local context = {
+    file = {
+      path = vim.api.nvim_buf_get_name(bufnr),
+      content = table.concat(vim.api.nvim_buf_get_lines(bufnr, 0, -1, false), "\n"),
+    },
+  }
+
+  if selection then
+    context.selection = { start = selection.start - 1, ["end"] = selection.finish - 1 }
+  end
+
+  return context
+end
+
+--- Get the snippets that would be sent as context with `query`
+---@param query string
+---@param context table: See |rpc.buffer_context|
+---@param callback fun(err: table?, snippets: { source: string, repo: string?, file: string, start: number, end: number, content: string }[]?)
+function rpc.context(query, context, callback)
+  req("cody/context", { query = query, context = context }, callback)
+end
+
+--- Start a new Cody conversation
+---@param opts { title: string?, language: string? }?
+---@param callback fun(err: table?, transcript: table?)
+function rpc.chat_new(opts, callback)
+  opts = opts or {}
+  req("cody/chat/new", { title = opts.title, language = opts.language }, callback)
+end
+
+--- Send a message in a conversation. When `on_delta` is set, the reply is
+--- streamed to it as it is generated.
+---@param id string: The conversation id
+---@param message string
+---@param opts { model: string?, context: table? }?: See |rpc.models| and |rpc.buffer_context|
+---@param on_delta fun(delta: string)?
+---@param callback fun(err: table?, data: { reply: string, citations: table[]?, usage: table? }?)
+---@return number?: The request id, to cancel the reply with |sg.request.cancel|
+function rpc.chat_submit(id, message, opts, on_delta, callback)
+  opts = opts or {}
+
+  local request = require "sg.request"
+  local request_id
+  local _, sent_id = request.request("cody/chat/submit", {
+    id = id,
+    message = message,
+    stream = on_delta ~= nil,
+    model = opts.model,
+    context = opts.context,
+  }, function(err, data)
+    -- The callback can run before the id is known, if the request fails to send
+    if request_id then
+      request.streams[request_id] = nil
+    end
+    callback(err, data)
+  end)
+
+  request_id = sent_id
+  if request_id and on_delta then
+    request.streams[request_id] = on_delta
+  end
+
+  return request_id
+end
+
+--- Get a conversation, with all of its messages
+---@param id string
+---@param callback fun(err: table?, transcript: table?)
+function rpc.chat_get(id, callback)
+  req("cody/chat/get", { id = id }, callback)
+end
+
+--- List the saved conversations, most recent first
+---@param callback fun(err: table?, chats: { id: string, title: string?, messages: number, updated_at: number }[]?)
+function rpc.chat_list(callback)
+  req("cody/chat/list", {}, callback)
+end
+
+--- Delete a conversation. The callback receives the remaining conversations.
+---@param id string
+---@param callback function
+function rpc.chat_delete(id, callback)
+  req("cody/chat/delete", { id = id }, callback)
 end
 
 return rpc
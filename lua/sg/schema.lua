@@ -0,0 +1,85 @@
+---@brief [[
+--- Checks requests against the params sg-nvim-agent expects.
+---
+--- The schema comes from the agent itself (the `$/schema` method, or
+--- `sg-nvim-agent --schema`), so it always matches the binary in use.
+---@brief ]]
+
+local M = {}
+
+local types = {
+  string = function(value)
+    return type(value) == "string"
+  end,
+  integer = function(value)
+    return type(value) == "number" and math.floor(value) == value
+  end,
+  number = function(value)
+    return type(value) == "number"
+  end,
+  boolean = function(value)
+    return type(value) == "boolean"
+  end,
+  array = vim.tbl_islist,
+  object = function(value)
+    return type(value) == "table"
+  end,
+}
+
+local check_value
+check_value = function(schema, value, path, problems)
+  if value == nil or value == vim.NIL or not schema.type then
+    return
+  end
+
+  if not types[schema.type](value) then
+    table.insert(problems, string.format("%s: expected %s, got %s", path, schema.type, type(value)))
+    return
+  end
+
+  if schema.type == "array" and schema.items then
+    for i, item in ipairs(value) do
+      check_value(schema.items, item, string.format("%s[%d]", path, i), problems)
+    end
+  end
+end
+
+--- Check `params` for `method`
+---@param schema table: The schema returned by `$/schema`
+---@param method string
+---@param params table?
+---@return string[]: Problems with the request, empty when it is valid
+M.check = function(schema, method, params)
+  local found = schema.methods[method]
+  if not found then
+    for name, candidate in pairs(schema.methods) do
+      if vim.tbl_contains(candidate.aliases or {}, method) then
+        found = schema.methods[name]
+      end
+    end
+  end
+
+  if not found then
+    return { string.format("unknown method: %s", method) }
+  end
+
+  params = params or {}
+
+  local problems = {}
+  for _, name in ipairs(found.params.required or {}) do
+    if params[name] == nil then
+      table.insert(problems, string.format("%s: missing required param", name))
+    end
+  end
+
+  for name, value in pairs(params) do
+    local property = found.params.properties[name]
+    if property then
+      check_value(property, value, name, problems)
+    end
+  end
+
+  return problems
+end
+
+return M
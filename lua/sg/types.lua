@@ -3,6 +3,8 @@ local M = {}
 ---@class SgPosition
 ---@field line number?
 ---@field col number?
+---@field end_line number?
+---@field end_col number?
 
 ---@class SgEntry
 ---@field type "file" | "directory" | "repo"
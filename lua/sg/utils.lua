@@ -183,6 +183,19 @@ utils.replace_markdown_link = function(str)
   end)
 end
 
+--- Percent-encode a path for an sg:// bufname, the same way as `encoding::encode_path`
+--- in sg-types: controls, non-ASCII bytes and characters that start a query or
+--- fragment are encoded, so the path survives being parsed again.
+---@param path string
+---@return string
+utils.encode_path = function(path)
+  local encoded = path:gsub('[%c "#%%?<>`{}\128-\255]', function(char)
+    return string.format("%%%02X", string.byte(char))
+  end)
+
+  return encoded
+end
+
 --- Start the LSP server, compatible with nvim 0.9 still
 ---@param cmd string[]: Command to start the LSP server.
 ---@param dispatchers? vim.lsp.rpc.Dispatchers
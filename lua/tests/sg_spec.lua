@@ -18,4 +18,38 @@ describe("sg-agent", function()
     eq(nil, err)
     eq("hello", echoed.message)
   end)
+
+  a.it("reports unknown methods", function()
+    local err, result
+    require("sg.request").request("sourcegraph/not_a_method", {}, function(err_, result_)
+      err = err_
+      result = result_ or true
+    end)
+
+    vim.wait(1000, function()
+      return err or result
+    end, 5)
+
+    eq(-32601, err.code)
+  end)
+
+  a.it("checks requests against the schema", function()
+    local err, schema
+    require("sg.request").request("$/schema", {}, function(err_, schema_)
+      err = err_
+      schema = schema_
+    end)
+
+    vim.wait(1000, function()
+      return err or schema
+    end, 5)
+
+    eq(nil, err)
+
+    local check = require("sg.schema").check
+    eq({}, check(schema, "Echo", { message = "hello" }))
+    eq({}, check(schema, "sourcegraph/dotcom_login", { port = 52068 }))
+    eq({ "message: missing required param" }, check(schema, "Echo", {}))
+    eq({ "delay: expected integer, got string" }, check(schema, "Echo", { message = "hi", delay = "1" }))
+  end)
 end)
@@ -69,4 +69,20 @@ describe("sg.utils", function()
       eq(false, ok)
     end)
   end)
+
+  describe("encode_path", function()
+    it("should leave plain paths alone", function()
+      eq("lua/sg/init.lua", utils.encode_path "lua/sg/init.lua")
+    end)
+
+    it("should encode characters that start a query or fragment", function()
+      eq("src/what%3F.rs", utils.encode_path "src/what?.rs")
+      eq("docs/%23readme.md", utils.encode_path "docs/#readme.md")
+      eq("with%20space/100%25.txt", utils.encode_path "with space/100%.txt")
+    end)
+
+    it("should encode non-ascii bytes", function()
+      eq("caf%C3%A9.md", utils.encode_path "café.md")
+    end)
+  end)
 end)
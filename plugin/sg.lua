@@ -35,8 +35,8 @@ end, {})
 --- Get prompted for endpoint and access_token if you don't
 --- want to set them via environment variables.
 ---
---- For enterprise instances, the only currently supported method
---- is environment variables.
+--- Without a bang, this opens the instance in your browser to create
+--- an access token, which is saved once the browser redirects back.
 ---
 --- If you want to force a particular endpoint + access token combination to be saved,
 --- use :SourcegraphLogin! and then follow the prompts.
@@ -55,37 +55,25 @@ vim.api.nvim_create_user_command("SourcegraphLogin", function(command)
         token = token,
         clear = token == "",
       }, function(err)
-        -- Update auth for this session
-        require("sg.auth").set(endpoint, token)
-
+        -- The session picks up the new credentials from the "credentials_changed" notification
         if err then
           vim.notify(string.format("[cody] Failed to update auth: %s", vim.inspect(err)))
         else
-          vim.notify "[cody] Updated Sourcegraph Auth Information. Please restart nvim."
+          vim.notify "[cody] Updated Sourcegraph Auth Information."
         end
       end)
     end
   end
 
-  if endpoint == "https://sourcegraph.com/" then
-    local port = 52068
-    local editor = "NEOVIM"
-    local redirect =
-      string.format("user/settings/tokens/new/callback?requestFrom=%s-%s", editor, port)
-
-    require("sg.rpc").dotcom_login(port, function(err, _)
-      if err then
-        vim.notify(string.format("Error occurred: %s", vim.inspect(err)))
-        return
-      end
+  require("sg.rpc").login(endpoint, 52068, function(err, data)
+    if err then
+      vim.notify(string.format("Error occurred: %s", vim.inspect(err)))
+      return
+    end
 
-      require("sg.utils").open(string.format("%s%s", endpoint, redirect))
-    end)
-  else
-    print(string.format("Found endpoint: '%s', which was not `https://sourcegraph.com`", endpoint))
-    print "Sorry, currently for enterprise instances, you'll need to use the environment variable methods"
-    print "Set `SRC_ENDPOINT` and `SRC_ACCESS_TOKEN` in your environment before starting neovim"
-  end
+    vim.notify "[cody] Waiting for login in the browser..."
+    require("sg.utils").open(data.url)
+  end)
 end, {
   desc = "Login and store credentials for later use (an alternative to using environment variables). Use <bang> to store a password",
   bang = true,
@@ -144,8 +132,22 @@ end, {
 ---@command SourcegraphLink [[
 --- Get a sourcegraph link to the current repo + file + line.
 --- Automatically adds it to your '+' register
+---
+--- Pass `permalink` to pin the link to the current commit, and `code_host`
+--- to link to the code host (GitHub, GitLab, ...) instead of Sourcegraph.
 ---@command ]]
 vim.api.nvim_create_user_command("SourcegraphLink", function(args)
+  local opts = {}
+  for _, arg in ipairs(args.fargs) do
+    if arg ~= "permalink" and arg ~= "code_host" then
+      local message = "[sourcegraph] Unknown SourcegraphLink option: %s (expected permalink or code_host)"
+      vim.notify(string.format(message, arg), vim.log.levels.ERROR)
+      return
+    end
+
+    opts[arg] = true
+  end
+
   print "requesting link..."
 
   local callback = function(err, link)
@@ -166,7 +168,7 @@ vim.api.nvim_create_user_command("SourcegraphLink", function(args)
     local cursor = vim.api.nvim_win_get_cursor(0)
     local row, col = cursor[1], cursor[2]
     local range = { start_line = row, start_col = col, end_line = row, end_col = col }
-    require("sg.rpc").get_link(vim.api.nvim_buf_get_name(0), range, callback)
+    require("sg.rpc").get_link(vim.api.nvim_buf_get_name(0), range, callback, opts)
     return
   end
 
@@ -198,9 +200,13 @@ vim.api.nvim_create_user_command("SourcegraphLink", function(args)
     }
   end
 
-  require("sg.rpc").get_link(vim.api.nvim_buf_get_name(0), range, callback)
+  require("sg.rpc").get_link(vim.api.nvim_buf_get_name(0), range, callback, opts)
 end, {
   desc = "Get a sourcegraph link to the current location",
+  nargs = "*",
+  complete = function()
+    return { "permalink", "code_host" }
+  end,
   range = 2,
 })
 
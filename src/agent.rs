@@ -0,0 +1,386 @@
+//! The JSON-RPC server behind `sg-nvim-agent`.
+//!
+//! [`serve`] handles one editor connection over any [`Transport`]. The agent runs it once over
+//! stdio, or once per connection when listening on a socket, so several editor instances can share
+//! one process (and its caches, rate limits and credentials).
+
+use {
+    crate::{
+        auth::{get_access_token, get_endpoint},
+        network::Network,
+        nvim::{self, Notification, Notifier},
+    },
+    anyhow::Result,
+    futures::future::BoxFuture,
+    jsonrpc::{codes, Batch, Id, RPCErr, Transport},
+    serde::Serialize,
+    serde_json::{json, Value},
+    std::{
+        collections::HashMap,
+        sync::{Arc, Mutex as StdMutex},
+    },
+    tokio::{
+        sync::{broadcast::error::RecvError, Notify},
+        task::AbortHandle,
+    },
+};
+
+/// Handle requests from one editor until it disconnects.
+pub async fn serve(transport: Transport) -> Result<()> {
+    let Transport {
+        mut reader,
+        writer: stdout,
+    } = transport;
+
+    crate::auth::load().await;
+
+    // Initialize by letting neovim know which endpoint we are using
+    jsonrpc::write_msg(
+        &stdout,
+        nvim::Message::notification(Notification::Initialize {
+            endpoint: get_endpoint().ok(),
+        }),
+    )
+    .await?;
+
+    // Everything else the editor is told goes through the notifier, in the order it was sent
+    let (notifier, mut notifications) = Notifier::channel();
+    let notifications_stdout = stdout.clone();
+    let notifications_task = tokio::spawn(async move {
+        while let Some(notification) = notifications.recv().await {
+            let message = nvim::Message::notification(notification);
+            if jsonrpc::write_msg(&notifications_stdout, message)
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+
+    // Let neovim know whenever the credentials change, so it doesn't need to restart
+    let credentials_notifier = notifier.clone();
+    let mut credentials = crate::auth::subscribe();
+    let credentials_task = tokio::spawn(async move {
+        loop {
+            match credentials.recv().await {
+                // Only the latest credentials matter, so missed changes can be skipped
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => break,
+            }
+
+            credentials_notifier.send(Notification::CredentialsChanged {
+                endpoint: get_endpoint().ok(),
+                token: get_access_token().await,
+            });
+        }
+    });
+
+    // Let neovim know when an endpoint starts or stops rate limiting us
+    let rate_limit_notifier = notifier.clone();
+    let mut rate_limits = sg_gql::retry::subscribe();
+    let rate_limit_task = tokio::spawn(async move {
+        loop {
+            match rate_limits.recv().await {
+                Ok(rate_limit) => rate_limit_notifier.send(Notification::RateLimit(rate_limit)),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            };
+        }
+    });
+
+    let running: Running = Default::default();
+    let network = Network::default();
+
+    // Set when a response can't be written anymore, which means the editor is gone
+    let closed = Arc::new(Notify::new());
+
+    loop {
+        let text = tokio::select! {
+            text = jsonrpc::read_msg_text(&mut reader) => text,
+            _ = closed.notified() => break,
+        };
+
+        let text = match text {
+            Ok(Some(text)) => text,
+            Ok(None) => break,
+            Err(err) => {
+                eprintln!("[sg-nvim-agent] failed to read message {err:?}");
+                continue;
+            }
+        };
+
+        let batch = match serde_json::from_str::<Batch<Value>>(&text) {
+            Ok(batch) => batch,
+            Err(err) => {
+                let err = RPCErr::new(codes::PARSE_ERROR, err.to_string());
+                let _ = jsonrpc::write_err(&stdout, None, err).await;
+                continue;
+            }
+        };
+
+        if matches!(&batch, Batch::Many(messages) if messages.is_empty()) {
+            let err = RPCErr::new(codes::INVALID_REQUEST, "Empty batch");
+            let _ = jsonrpc::write_err(&stdout, None, err).await;
+            continue;
+        }
+
+        let is_batch = matches!(batch, Batch::Many(_));
+        let mut responses: Vec<BoxFuture<'static, Value>> = vec![];
+
+        for value in batch.into_vec() {
+            match serde_json::from_value::<nvim::Message>(value.clone()) {
+                Ok(nvim::Message::Request(request)) => {
+                    eprintln!("Recieved a message: {request:?}");
+                    responses.push(spawn_request(request, &notifier, &running, &network));
+                }
+                Ok(nvim::Message::Notification(jsonrpc::Notification {
+                    call: Notification::Cancel { id },
+                    ..
+                })) => {
+                    // The request's own response becomes the cancellation error
+                    let task = running.lock().expect("to unlock running").remove(&id);
+                    match task {
+                        Some(task) => task.abort(),
+                        // A browser login outlives the request that started it, but can still
+                        // be cancelled by its id
+                        None => {
+                            tokio::spawn(async move { crate::login::cancel(&id).await });
+                        }
+                    }
+                }
+                Ok(message) => {
+                    eprintln!("Somehow got a not request: {message:?}");
+                }
+                Err(err) => {
+                    // Notifications can't be answered, so only requests get an error back. Values
+                    // that aren't objects can't be told apart, so they get one without an id.
+                    let id = jsonrpc::find_id(&value.to_string());
+                    if id.is_none() && value.is_object() {
+                        eprintln!("[sg-nvim-agent] invalid notification: {err}");
+                        continue;
+                    }
+
+                    let err = RPCErr::new(codes::INVALID_REQUEST, err.to_string());
+                    let response = to_value(jsonrpc::Response::<Value>::err(id, err));
+                    responses.push(Box::pin(async move { response }));
+                }
+            }
+        }
+
+        if responses.is_empty() {
+            continue;
+        }
+
+        // Batches are answered with a single array, once every request in it is done
+        let stdout = stdout.clone();
+        let closed = closed.clone();
+        tokio::spawn(async move {
+            let mut responses = futures::future::join_all(responses).await;
+            let sent = match is_batch {
+                true => jsonrpc::write_msg(&stdout, responses).await,
+                false => jsonrpc::write_msg(&stdout, responses.remove(0)).await,
+            };
+
+            if sent.is_err() {
+                eprintln!("Failed to send response, must be closed");
+                closed.notify_one();
+            }
+        });
+    }
+
+    // Nobody is left to answer, so stop everything that was started for this editor
+    for (_, task) in running.lock().expect("to unlock running").drain() {
+        task.abort();
+    }
+
+    credentials_task.abort();
+    rate_limit_task.abort();
+    notifications_task.abort();
+
+    Ok(())
+}
+
+/// Requests that are still running, so that they can be cancelled.
+type Running = Arc<StdMutex<HashMap<Id, AbortHandle>>>;
+
+/// Run `request` as its own task, so slow requests don't block the messages after them and can
+/// be cancelled. Resolves to the response.
+fn spawn_request(
+    request: nvim::Request,
+    notifier: &Notifier,
+    running: &Running,
+    network: &Network,
+) -> BoxFuture<'static, Value> {
+    let id = request.id.clone();
+    let notifier = notifier.clone();
+    let task_running = running.clone();
+    let task_id = id.clone();
+
+    let mut running = running.lock().expect("to unlock running");
+    let task = tokio::spawn(network.scope(async move {
+        let response = request.respond(&notifier).await;
+        task_running
+            .lock()
+            .expect("to unlock running")
+            .remove(&task_id);
+
+        response_value(&notifier, task_id, response)
+    }));
+
+    running.insert(id.clone(), task.abort_handle());
+
+    Box::pin(async move {
+        match task.await {
+            Ok(response) => response,
+            Err(_) => {
+                let message = format!("Request {id} was cancelled");
+                let err = RPCErr::new(codes::REQUEST_CANCELLED, message);
+                to_value(jsonrpc::Response::<Value>::err(Some(id), err))
+            }
+        }
+    })
+}
+
+fn response_value(notifier: &Notifier, id: Id, response: Result<nvim::Response>) -> Value {
+    let err = match response {
+        Ok(response) => return to_value(response),
+        Err(err) => err,
+    };
+
+    // Methods that fail with an `RPCErr` picked their own code
+    if let Some(rpc_err) = err.downcast_ref::<RPCErr>() {
+        return to_value(jsonrpc::Response::<Value>::err(Some(id), rpc_err.clone()));
+    }
+
+    let mut rpc_err = RPCErr::new(codes::INTERNAL_ERROR, err.to_string());
+    if let Some(auth_err) = err.downcast_ref::<sg_gql::AuthError>() {
+        notifier.send(Notification::AuthInvalid {
+            endpoint: auth_err.endpoint.clone(),
+            message: auth_err.to_string(),
+        });
+
+        rpc_err = rpc_err.with_data(json!({
+            "endpoint": auth_err.endpoint,
+            "status": auth_err.status,
+        }));
+    }
+
+    to_value(jsonrpc::Response::<Value>::err(Some(id), rpc_err))
+}
+
+fn to_value(response: impl Serialize) -> Value {
+    serde_json::to_value(response).expect("responses to serialize")
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, jsonrpc::read_msg};
+
+    /// Start a server in memory, and skip its `initialize` notification.
+    async fn connect() -> Transport {
+        let (mut client, server) = Transport::memory();
+        tokio::spawn(serve(server));
+
+        let initialize: Value = read_msg(&mut client.reader).await.unwrap().unwrap();
+        assert_eq!(initialize["method"], "initialize");
+
+        client
+    }
+
+    #[tokio::test]
+    async fn responds_with_request_id() {
+        let mut client = connect().await;
+
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": 7,
+            "method": "Echo",
+            "params": { "message": "hello" },
+        });
+        jsonrpc::write_msg(&client.writer, request).await.unwrap();
+
+        let response: Value = read_msg(&mut client.reader).await.unwrap().unwrap();
+        assert_eq!(response["id"], 7);
+        assert_eq!(response["result"]["message"], "hello");
+    }
+
+    #[tokio::test]
+    async fn answers_batches_with_an_array() {
+        let mut client = connect().await;
+
+        let batch = json!([
+            { "jsonrpc": "2.0", "id": 1, "method": "Echo", "params": { "message": "one" } },
+            { "jsonrpc": "2.0", "id": 2, "method": "not/a/method", "params": {} },
+        ]);
+        jsonrpc::write_msg(&client.writer, batch).await.unwrap();
+
+        let response: Value = read_msg(&mut client.reader).await.unwrap().unwrap();
+        assert_eq!(response[0]["result"]["message"], "one");
+        assert_eq!(response[1]["id"], 2);
+        assert_eq!(response[1]["error"]["code"], codes::METHOD_NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn empty_batches_get_a_single_error() {
+        let mut client = connect().await;
+
+        jsonrpc::write_msg(&client.writer, json!([])).await.unwrap();
+
+        let response: Value = read_msg(&mut client.reader).await.unwrap().unwrap();
+        assert_eq!(response["id"], Value::Null);
+        assert_eq!(response["error"]["code"], codes::INVALID_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn batch_values_that_are_not_messages_get_an_error_each() {
+        let mut client = connect().await;
+
+        let batch = json!([
+            1,
+            { "jsonrpc": "2.0", "id": 1, "method": "Echo", "params": { "message": "one" } },
+            "two",
+        ]);
+        jsonrpc::write_msg(&client.writer, batch).await.unwrap();
+
+        let response: Value = read_msg(&mut client.reader).await.unwrap().unwrap();
+        assert_eq!(response.as_array().unwrap().len(), 3);
+        assert_eq!(response[0]["id"], Value::Null);
+        assert_eq!(response[0]["error"]["code"], codes::INVALID_REQUEST);
+        assert_eq!(response[1]["result"]["message"], "one");
+        assert_eq!(response[2]["id"], Value::Null);
+        assert_eq!(response[2]["error"]["code"], codes::INVALID_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_params() {
+        let mut client = connect().await;
+
+        let request = json!({ "jsonrpc": "2.0", "id": 3, "method": "Echo", "params": {} });
+        jsonrpc::write_msg(&client.writer, request).await.unwrap();
+
+        let response: Value = read_msg(&mut client.reader).await.unwrap().unwrap();
+        assert_eq!(response["id"], 3);
+        assert_eq!(response["error"]["code"], codes::INVALID_PARAMS);
+    }
+
+    #[tokio::test]
+    async fn cancelled_requests_get_an_error() {
+        let mut client = connect().await;
+
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": "slow",
+            "method": "Echo",
+            "params": { "message": "hello", "delay": 30 },
+        });
+        jsonrpc::write_msg(&client.writer, request).await.unwrap();
+
+        let cancel = json!({ "jsonrpc": "2.0", "method": "$/cancel", "params": { "id": "slow" } });
+        jsonrpc::write_msg(&client.writer, cancel).await.unwrap();
+
+        let response: Value = read_msg(&mut client.reader).await.unwrap().unwrap();
+        assert_eq!(response["id"], "slow");
+        assert_eq!(response["error"]["code"], codes::REQUEST_CANCELLED);
+    }
+}
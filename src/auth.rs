@@ -2,94 +2,598 @@ use {
     anyhow::{Context, Result},
     once_cell::sync::Lazy,
     serde::{Deserialize, Serialize},
-    std::sync::Mutex,
+    std::{
+        collections::{BTreeMap, HashMap},
+        future::Future,
+        process::Stdio,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant, SystemTime},
+    },
+    tokio::sync::broadcast,
 };
 
-#[derive(Serialize, Deserialize, Default)]
+mod store;
+
+pub use store::Backend;
+
+/// Name of the profile used when credentials are saved without naming one.
+pub const DEFAULT_PROFILE: &str = "default";
+
+#[derive(Serialize, Deserialize, Default, Clone)]
 pub struct CodyCredentials {
     pub endpoint: Option<String>,
     pub token: Option<String>,
+
+    /// Command that prints a (short-lived) token, used instead of `token` when set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_command: Option<String>,
 }
 
-static ACCESS_TOKEN: Lazy<Mutex<Option<String>>> = Lazy::new(|| {
-    fn get_token() -> Option<String> {
-        if let Ok(token) = std::env::var("SRC_ACCESS_TOKEN") {
-            if !token.is_empty() {
-                return Some(token);
-            }
-        }
+/// All saved profiles, stored as a single JSON object in the keyring.
+#[derive(Serialize, Deserialize, Default, Clone)]
+struct Profiles {
+    active: Option<String>,
+    profiles: BTreeMap<String, CodyCredentials>,
 
-        if let Some(CodyCredentials {
-            token: Some(token), ..
-        }) = get_credentials()
-        {
-            return Some(token);
-        };
+    /// Repository hosts (e.g. `ghe.corp.com`) mapped to the profile that serves them.
+    #[serde(default)]
+    routes: BTreeMap<String, String>,
+}
 
-        None
+impl Profiles {
+    fn active_name(&self) -> &str {
+        self.active.as_deref().unwrap_or(DEFAULT_PROFILE)
     }
 
-    Mutex::new(get_token())
-});
+    fn active_credentials(&self) -> CodyCredentials {
+        self.profiles
+            .get(self.active_name())
+            .cloned()
+            .unwrap_or_default()
+    }
+}
 
-static ENDPOINT: Lazy<Mutex<Option<String>>> = Lazy::new(|| {
-    fn get_token() -> Option<String> {
-        if let Ok(token) = std::env::var("SRC_ENDPOINT") {
-            if !token.is_empty() {
-                return Some(token);
-            }
-        }
+/// Information about a saved profile that is safe to send to the editor.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProfileInfo {
+    pub name: String,
+    pub endpoint: Option<String>,
+    pub token_set: bool,
+    pub active: bool,
+    pub hosts: Vec<String>,
+}
+
+/// The endpoint and token that a request should be sent with.
+pub struct Instance {
+    pub endpoint: String,
+    pub token: Option<String>,
+
+    /// The command the token came from, so that it can be refreshed if it is rejected.
+    pub token_command: Option<String>,
+}
 
-        if let Some(CodyCredentials {
-            endpoint: Some(token),
-            ..
-        }) = get_credentials()
-        {
-            return Some(token);
-        };
+struct AuthState {
+    /// The credentials used when a request does not name a profile.
+    current: CodyCredentials,
+    profiles: Profiles,
+    backend: Backend,
 
-        None
+    /// Why the saved profiles could not be loaded, if they couldn't.
+    error: Option<String>,
+}
+
+/// The credentials set by `SRC_ENDPOINT`, `SRC_ACCESS_TOKEN` and `SRC_ACCESS_TOKEN_COMMAND`.
+fn env_credentials() -> CodyCredentials {
+    fn from_env(name: &str) -> Option<String> {
+        std::env::var(name).ok().filter(|value| !value.is_empty())
     }
 
-    Mutex::new(get_token())
+    CodyCredentials {
+        endpoint: from_env("SRC_ENDPOINT"),
+        token: from_env("SRC_ACCESS_TOKEN"),
+        token_command: from_env("SRC_ACCESS_TOKEN_COMMAND"),
+    }
+}
+
+static STATE: Lazy<Mutex<AuthState>> = Lazy::new(|| {
+    // If the credentials can't be read, the backend is kept (so it isn't silently swapped for one
+    // that forgets everything), and nothing is saved over them until they can be read again.
+    let (backend, loaded) = match Backend::detect() {
+        Ok(backend) => {
+            let profiles = get_profiles(&backend);
+            (backend, profiles)
+        }
+        Err(err) => (Backend::Env, Err(err)),
+    };
+
+    let (profiles, error) = match loaded {
+        Ok(profiles) => (profiles.unwrap_or_default(), None),
+        Err(err) => {
+            eprintln!("[sg] failed to load credentials: {err:#}");
+            (Profiles::default(), Some(format!("{err:#}")))
+        }
+    };
+
+    let active = profiles.active_credentials();
+
+    // Environment variables take precedence over the saved active profile on startup
+    let env = env_credentials();
+    let token_command = match env.token {
+        Some(_) => env.token_command,
+        None => env.token_command.or(active.token_command),
+    };
+
+    let current = CodyCredentials {
+        endpoint: env.endpoint.or(active.endpoint),
+        token: env.token.or(active.token),
+        token_command,
+    };
+
+    Mutex::new(AuthState {
+        current,
+        profiles,
+        backend,
+        error,
+    })
 });
 
-pub fn get_access_token() -> Option<String> {
-    ACCESS_TOKEN.lock().expect("to unlock access token").clone()
+/// Load the saved credentials on the blocking thread pool, since that can wait on the keyring or
+/// run a password manager. Called before any requests are handled, so that the first one doesn't
+/// block the runtime while they load.
+pub async fn load() {
+    let _ = tokio::task::spawn_blocking(|| Lazy::force(&STATE)).await;
 }
 
-pub fn get_endpoint() -> String {
-    ENDPOINT
-        .lock()
-        .expect("to unlock endpoint")
-        .clone()
+/// Sends the current credentials every time they change.
+static CHANGES: Lazy<broadcast::Sender<CodyCredentials>> = Lazy::new(|| broadcast::channel(16).0);
+
+/// Receive the current credentials every time they change (by logging in, switching profiles,
+/// ...), so that clients can be refreshed without restarting.
+pub fn subscribe() -> broadcast::Receiver<CodyCredentials> {
+    CHANGES.subscribe()
+}
+
+/// Save `profiles`, and only once they are saved, switch to `credentials` (if given) and tell
+/// subscribers. Nothing changes if saving fails, or if the saved profiles couldn't be loaded (so
+/// they aren't overwritten).
+fn update(
+    state: &mut AuthState,
+    profiles: Profiles,
+    credentials: Option<CodyCredentials>,
+) -> Result<()> {
+    if let Some(error) = &state.error {
+        anyhow::bail!(
+            "Not saving credentials, because the saved ones could not be loaded: {error}"
+        );
+    }
+
+    let previous = std::mem::replace(&mut state.profiles, profiles);
+    if let Err(err) = save_profiles(state) {
+        state.profiles = previous;
+        return Err(err);
+    }
+
+    if let Some(credentials) = credentials {
+        state.current = credentials.clone();
+
+        // No receivers just means that nobody is listening for changes
+        let _ = CHANGES.send(credentials);
+    }
+
+    Ok(())
+}
+
+tokio::task_local! {
+    static PROFILE: String;
+}
+
+/// Run `f` with the credentials of `profile`, instead of the current credentials.
+pub async fn with_profile<F: Future>(profile: Option<String>, f: F) -> F::Output {
+    match profile {
+        Some(profile) => PROFILE.scope(profile, f).await,
+        None => f.await,
+    }
+}
+
+/// Pick the credentials for a request. In order of precedence, this is the profile the request
+/// was sent with, the profile routed to the host of `remote`, or the current credentials.
+///
+/// Fails if the request names a profile that doesn't exist, rather than sending it anonymously.
+fn credentials(remote: Option<&str>) -> Result<CodyCredentials> {
+    let state = STATE.lock().expect("to unlock auth state");
+    let profiles = &state.profiles;
+
+    if let Ok(credentials) = PROFILE.try_with(|profile| {
+        profiles
+            .profiles
+            .get(profile)
+            .cloned()
+            .with_context(|| format!("Unknown profile: {profile}"))
+    }) {
+        return credentials;
+    }
+
+    let host = remote.map(|remote| remote.split('/').next().unwrap_or(remote));
+    Ok(host
+        .and_then(|host| profiles.routes.get(host))
+        .and_then(|profile| profiles.profiles.get(profile))
+        .cloned()
+        .unwrap_or_else(|| state.current.clone()))
+}
+
+fn normalize_endpoint(endpoint: Option<String>) -> String {
+    endpoint
         .unwrap_or_else(|| "https://sourcegraph.com".to_string())
         .trim_end_matches('/')
         .to_string()
 }
 
-fn get_entry() -> Result<keyring::Entry> {
-    let username = whoami::username();
-    keyring::Entry::new("cody-access-token", &username).context("getting keyring entry")
+pub async fn get_access_token() -> Option<String> {
+    resolve_token(&credentials(None).ok()?).await
 }
 
-fn get_credentials() -> Option<CodyCredentials> {
-    let entry = get_entry().ok()?;
-    let token = entry.get_password().ok()?;
-    serde_json::from_str(&token).ok()
+pub fn get_endpoint() -> Result<String> {
+    Ok(normalize_endpoint(credentials(None)?.endpoint))
 }
 
-pub fn set_credentials(credentials: CodyCredentials) -> Result<()> {
-    if let Some(token) = &credentials.token {
-        std::env::set_var("SRC_ACCESS_TOKEN", token);
+impl Instance {
+    /// The instance that `credentials` point to, running their `token_command` if needed.
+    pub async fn resolve(credentials: CodyCredentials) -> Self {
+        Self {
+            token: resolve_token(&credentials).await,
+            endpoint: normalize_endpoint(credentials.endpoint),
+            token_command: credentials.token_command,
+        }
     }
+}
+
+/// A token printed by a `token_command`, kept until it expires or is rejected.
+struct CachedToken {
+    token: String,
+    expires: Option<Instant>,
+}
+
+/// Tokens are refreshed this long before they expire, so they don't expire mid-request.
+const EXPIRY_MARGIN: Duration = Duration::from_secs(30);
 
-    if let Some(endpoint) = &credentials.endpoint {
-        std::env::set_var("SRC_ENDPOINT", endpoint);
+/// How long a `token_command` may run, e.g. while a password manager asks to be unlocked.
+const TOKEN_COMMAND_TIMEOUT: Duration = Duration::from_secs(60);
+
+static TOKENS: Lazy<tokio::sync::Mutex<HashMap<String, CachedToken>>> = Lazy::new(Default::default);
+
+/// A lock for each `token_command`, held while it runs.
+static RUNNING: Lazy<tokio::sync::Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>> =
+    Lazy::new(Default::default);
+
+/// What a `token_command` prints: either just the token, or JSON with the token and when it
+/// expires (`expires_in` seconds from now, or at the unix timestamp `expires_at`).
+#[derive(Deserialize)]
+struct CommandToken {
+    token: String,
+    #[serde(default)]
+    expires_in: Option<u64>,
+    #[serde(default)]
+    expires_at: Option<u64>,
+}
+
+async fn resolve_token(credentials: &CodyCredentials) -> Option<String> {
+    match &credentials.token_command {
+        Some(command) => match command_token(command).await {
+            Ok(token) => Some(token),
+            Err(err) => {
+                eprintln!("[sg] failed to get token from command: {err:#}");
+                None
+            }
+        },
+        None => credentials.token.clone(),
+    }
+}
+
+/// The token cached from `command`, unless it has expired.
+async fn cached_token(command: &str) -> Option<String> {
+    let tokens = TOKENS.lock().await;
+    let cached = tokens.get(command)?;
+    let expired = matches!(cached.expires, Some(expires) if expires <= Instant::now());
+    (!expired).then(|| cached.token.clone())
+}
+
+/// Get the token from `command`, running it again only if the cached token has expired.
+async fn command_token(command: &str) -> Result<String> {
+    if let Some(token) = cached_token(command).await {
+        return Ok(token);
     }
 
-    let entry = get_entry()?;
-    entry
-        .set_password(&serde_json::to_string(&credentials)?)
-        .context("set_credentials")
+    // Concurrent requests wait for the one running the command and use its token, rather than
+    // running it again. Requests using other commands (or cached tokens) don't wait.
+    let running = RUNNING
+        .lock()
+        .await
+        .entry(command.to_string())
+        .or_default()
+        .clone();
+    let _running = running.lock().await;
+    if let Some(token) = cached_token(command).await {
+        return Ok(token);
+    }
+
+    let output = tokio::process::Command::from(store::shell(command))
+        .stderr(Stdio::inherit())
+        .kill_on_drop(true)
+        .output();
+    let output = tokio::time::timeout(TOKEN_COMMAND_TIMEOUT, output)
+        .await
+        .with_context(|| {
+            format!(
+                "Token command timed out after {}s: {command}",
+                TOKEN_COMMAND_TIMEOUT.as_secs()
+            )
+        })?
+        .with_context(|| format!("running: {command}"))?;
+
+    if !output.status.success() {
+        anyhow::bail!("Token command failed ({}): {command}", output.status);
+    }
+
+    let stdout = String::from_utf8(output.stdout)?;
+    let stdout = stdout.trim();
+    let parsed = serde_json::from_str(stdout).unwrap_or_else(|_| CommandToken {
+        token: stdout.to_string(),
+        expires_in: None,
+        expires_at: None,
+    });
+
+    if parsed.token.is_empty() {
+        anyhow::bail!("Token command did not print a token: {command}");
+    }
+
+    let lifetime = match (parsed.expires_in, parsed.expires_at) {
+        (Some(seconds), _) => Some(Duration::from_secs(seconds)),
+        (None, Some(timestamp)) => Some(
+            (SystemTime::UNIX_EPOCH + Duration::from_secs(timestamp))
+                .duration_since(SystemTime::now())
+                .unwrap_or_default(),
+        ),
+        (None, None) => None,
+    };
+
+    TOKENS.lock().await.insert(
+        command.to_string(),
+        CachedToken {
+            token: parsed.token.clone(),
+            expires: lifetime
+                .map(|lifetime| Instant::now() + lifetime.saturating_sub(EXPIRY_MARGIN)),
+        },
+    );
+
+    Ok(parsed.token)
+}
+
+/// Forget the cached token from `command`, so that the next request runs it again.
+pub async fn invalidate_token(command: &str) {
+    TOKENS.lock().await.remove(command);
+}
+
+/// Get the instance to send a request for `remote` to, see [`credentials`].
+pub async fn get_instance(remote: Option<&str>) -> Result<Instance> {
+    Ok(Instance::resolve(credentials(remote)?).await)
+}
+
+/// Every endpoint that is configured, either in a profile or as the current endpoint. The longest
+/// endpoints come first, so that an instance served under a path is matched before its host.
+pub fn get_endpoints() -> Vec<String> {
+    let state = STATE.lock().expect("to unlock auth state");
+    let mut endpoints = state
+        .profiles
+        .profiles
+        .values()
+        .filter_map(|credentials| credentials.endpoint.clone())
+        .chain(state.current.endpoint.clone())
+        .map(|endpoint| normalize_endpoint(Some(endpoint)))
+        .collect::<Vec<_>>();
+
+    endpoints.sort();
+    endpoints.dedup();
+    endpoints.sort_by_key(|endpoint| std::cmp::Reverse(endpoint.len()));
+    endpoints
+}
+
+/// The rest of `url` if it is on `endpoint`. A bare prefix isn't enough, so that
+/// `https://sourcegraph.company.com` isn't mistaken for a URL on `https://sourcegraph.com`.
+pub fn strip_endpoint<'a>(url: &'a str, endpoint: &str) -> Option<&'a str> {
+    let rest = url.strip_prefix(endpoint)?;
+    match rest.chars().next() {
+        None | Some('/' | '?' | '#') => Some(rest),
+        Some(_) => None,
+    }
+}
+
+/// Find the profile whose endpoint `url` belongs to, if any.
+pub fn profile_for_url(url: &str) -> Option<String> {
+    let state = STATE.lock().expect("to unlock auth state");
+    state
+        .profiles
+        .profiles
+        .iter()
+        .filter_map(|(name, credentials)| {
+            let endpoint = normalize_endpoint(Some(credentials.endpoint.clone()?));
+            strip_endpoint(url, &endpoint).map(|_| (name, endpoint.len()))
+        })
+        .max_by_key(|(_, len)| *len)
+        .map(|(name, _)| name.clone())
+}
+
+fn get_profiles(backend: &Backend) -> Result<Option<Profiles>> {
+    let Some(stored) = backend.load()? else {
+        return Ok(None);
+    };
+
+    let default_profile = |credentials| Profiles {
+        active: None,
+        profiles: BTreeMap::from([(DEFAULT_PROFILE.to_string(), credentials)]),
+        routes: BTreeMap::new(),
+    };
+
+    // Older versions stored a single set of credentials, which becomes the default profile
+    if let Ok(profiles) = serde_json::from_str(&stored) {
+        return Ok(Some(profiles));
+    }
+
+    if let Ok(credentials) = serde_json::from_str::<CodyCredentials>(&stored) {
+        return Ok(Some(default_profile(credentials)));
+    }
+
+    // Password managers usually hold just the token, e.g. `op read op://vault/sourcegraph/token`
+    match backend {
+        Backend::Command { .. } => Ok(Some(default_profile(CodyCredentials {
+            token: Some(stored),
+            ..Default::default()
+        }))),
+        _ => anyhow::bail!("Saved credentials are not valid JSON"),
+    }
+}
+
+fn save_profiles(state: &AuthState) -> Result<()> {
+    state
+        .backend
+        .save(&serde_json::to_string(&state.profiles)?)
+        .with_context(|| format!("saving credentials to {}", state.backend.name()))
+}
+
+/// The backend that credentials are saved to, and why loading them failed (if it did).
+pub fn get_backend() -> (Backend, Option<String>) {
+    let state = STATE.lock().expect("to unlock auth state");
+    (state.backend.clone(), state.error.clone())
+}
+
+/// Save credentials to the active profile, or to `profile` (making it the active profile).
+pub fn set_credentials(credentials: CodyCredentials, profile: Option<String>) -> Result<()> {
+    let mut state = STATE.lock().expect("to unlock auth state");
+    let name = profile.unwrap_or_else(|| state.profiles.active_name().to_string());
+
+    let mut profiles = state.profiles.clone();
+    profiles.active = Some(name.clone());
+    profiles.profiles.insert(name, credentials.clone());
+
+    update(&mut state, profiles, Some(credentials))
+}
+
+pub fn list_profiles() -> Vec<ProfileInfo> {
+    let state = STATE.lock().expect("to unlock auth state");
+    let active = state.profiles.active_name();
+
+    state
+        .profiles
+        .profiles
+        .iter()
+        .map(|(name, credentials)| ProfileInfo {
+            name: name.clone(),
+            endpoint: credentials.endpoint.clone(),
+            token_set: credentials.token.is_some() || credentials.token_command.is_some(),
+            active: name == active,
+            hosts: state
+                .profiles
+                .routes
+                .iter()
+                .filter(|(_, profile)| *profile == name)
+                .map(|(host, _)| host.clone())
+                .collect(),
+        })
+        .collect()
+}
+
+/// Make `name` the active profile, so that it is used for all requests that don't name one.
+pub fn switch_profile(name: &str) -> Result<()> {
+    let mut state = STATE.lock().expect("to unlock auth state");
+    let credentials = state
+        .profiles
+        .profiles
+        .get(name)
+        .cloned()
+        .with_context(|| format!("No profile named: {name}"))?;
+
+    let mut profiles = state.profiles.clone();
+    profiles.active = Some(name.to_string());
+
+    update(&mut state, profiles, Some(credentials))
+}
+
+pub fn remove_profile(name: &str) -> Result<()> {
+    let mut state = STATE.lock().expect("to unlock auth state");
+    let mut profiles = state.profiles.clone();
+    profiles
+        .profiles
+        .remove(name)
+        .with_context(|| format!("No profile named: {name}"))?;
+
+    let mut credentials = None;
+    if profiles.active_name() == name {
+        profiles.active = None;
+        credentials = Some(env_credentials());
+    }
+
+    profiles.routes.retain(|_, profile| profile != name);
+
+    update(&mut state, profiles, credentials)
+}
+
+/// Send requests for repositories on `host` to `profile`, or remove the route if `profile` is
+/// `None`.
+pub fn set_route(host: &str, profile: Option<String>) -> Result<()> {
+    let mut state = STATE.lock().expect("to unlock auth state");
+    let mut profiles = state.profiles.clone();
+    match profile {
+        Some(profile) => {
+            if !profiles.profiles.contains_key(&profile) {
+                anyhow::bail!("No profile named: {profile}");
+            }
+
+            profiles.routes.insert(host.to_string(), profile);
+        }
+        None => {
+            profiles.routes.remove(host);
+        }
+    }
+
+    update(&mut state, profiles, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip_endpoint_matches_whole_hosts() {
+        let endpoint = "https://sourcegraph.com";
+
+        assert_eq!(
+            strip_endpoint("https://sourcegraph.com/github.com/a/b", endpoint),
+            Some("/github.com/a/b")
+        );
+        assert_eq!(
+            strip_endpoint("https://sourcegraph.com", endpoint),
+            Some("")
+        );
+        assert_eq!(
+            strip_endpoint("https://sourcegraph.company.com/github.com/a/b", endpoint),
+            None
+        );
+        assert_eq!(
+            strip_endpoint("https://sourcegraph.com:8443/a", endpoint),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_run_a_token_command_once() {
+        let runs = std::env::temp_dir().join(format!("sg-token-runs-{}", std::process::id()));
+        let command = format!("echo run >> '{}'; sleep 0.2; echo token", runs.display());
+
+        let (a, b) = tokio::join!(command_token(&command), command_token(&command));
+        assert_eq!(a.unwrap(), "token");
+        assert_eq!(b.unwrap(), "token");
+
+        let count = std::fs::read_to_string(&runs).unwrap().lines().count();
+        let _ = std::fs::remove_file(&runs);
+        assert_eq!(count, 1);
+    }
 }
@@ -0,0 +1,232 @@
+//! Where saved profiles are kept.
+//!
+//! The OS keyring is used when it is available. Headless machines and containers often have no
+//! Secret Service, so credentials can instead be kept in an encrypted file, read from (and written
+//! to) a password manager command, or only read from the environment.
+//!
+//! The backend can be chosen with `SG_CREDENTIAL_STORE` (`keyring`, `file`, `command` or `env`).
+//! The command backend runs `SG_CREDENTIAL_COMMAND` to read the stored value, and pipes the value
+//! to `SG_CREDENTIAL_STORE_COMMAND` on stdin to save it, for example:
+//!
+//! ```sh
+//! SG_CREDENTIAL_STORE=command
+//! SG_CREDENTIAL_COMMAND="pass show sourcegraph/nvim"
+//! SG_CREDENTIAL_STORE_COMMAND="pass insert --multiline --force sourcegraph/nvim"
+//! ```
+
+use {
+    anyhow::{Context, Result},
+    chacha20poly1305::{
+        aead::{Aead, AeadCore, KeyInit, OsRng},
+        ChaCha20Poly1305, Key, Nonce,
+    },
+    serde::{Deserialize, Serialize},
+    std::{
+        io::Write,
+        path::{Path, PathBuf},
+        process::{Command, Stdio},
+    },
+};
+
+const NONCE_LENGTH: usize = 12;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Backend {
+    /// The OS keyring (Keychain, Secret Service, Credential Manager).
+    Keyring,
+
+    /// A file encrypted with a key stored in a separate file, so that the credentials are not
+    /// readable from the file alone (for example from backups or a dotfiles repository).
+    File { path: PathBuf, key: PathBuf },
+
+    /// A password manager command, such as `pass` or `op`.
+    Command { get: String, set: Option<String> },
+
+    /// Only read `SRC_ENDPOINT` and `SRC_ACCESS_TOKEN`, and keep any changes for this session.
+    Env,
+}
+
+impl Backend {
+    /// Use the backend from `SG_CREDENTIAL_STORE`, or the keyring if it can be reached, falling
+    /// back to the encrypted file.
+    pub fn detect() -> Result<Self> {
+        let configured = std::env::var("SG_CREDENTIAL_STORE").unwrap_or_default();
+        match configured.as_str() {
+            "keyring" => Ok(Self::Keyring),
+            "file" => Self::file(),
+            "command" => Ok(Self::Command {
+                get: std::env::var("SG_CREDENTIAL_COMMAND")
+                    .context("SG_CREDENTIAL_COMMAND must be set for the command store")?,
+                set: std::env::var("SG_CREDENTIAL_STORE_COMMAND").ok(),
+            }),
+            "env" => Ok(Self::Env),
+            "" => match keyring_entry()?.get_password() {
+                Ok(_) | Err(keyring::Error::NoEntry) => Ok(Self::Keyring),
+                Err(err) => {
+                    eprintln!("[sg] keyring is not available, using encrypted file: {err}");
+                    Self::file()
+                }
+            },
+            other => anyhow::bail!("Unknown SG_CREDENTIAL_STORE: {other}"),
+        }
+    }
+
+    fn file() -> Result<Self> {
+        let config = dirs::config_dir().context("Unable to find config directory")?;
+        let data = dirs::data_dir().context("Unable to find data directory")?;
+
+        Ok(Self::File {
+            path: config.join("sg.nvim").join("credentials"),
+            key: data.join("sg.nvim").join("credentials.key"),
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Keyring => "keyring",
+            Self::File { .. } => "file",
+            Self::Command { .. } => "command",
+            Self::Env => "env",
+        }
+    }
+
+    /// Read the stored value, or `None` if nothing has been saved yet.
+    pub fn load(&self) -> Result<Option<String>> {
+        match self {
+            Self::Keyring => match keyring_entry()?.get_password() {
+                Ok(value) => Ok(Some(value)),
+                Err(keyring::Error::NoEntry) => Ok(None),
+                Err(err) => Err(err).context("reading keyring"),
+            },
+            Self::File { path, key } => {
+                if !path.exists() {
+                    return Ok(None);
+                }
+
+                let contents = std::fs::read(path).context("reading credentials file")?;
+                if contents.len() < NONCE_LENGTH {
+                    anyhow::bail!("Credentials file is corrupted: {}", path.display());
+                }
+
+                let (nonce, ciphertext) = contents.split_at(NONCE_LENGTH);
+                let plaintext = cipher(key)?
+                    .decrypt(Nonce::from_slice(nonce), ciphertext)
+                    .map_err(|_| anyhow::anyhow!("Unable to decrypt {}", path.display()))?;
+
+                Ok(Some(String::from_utf8(plaintext)?))
+            }
+            Self::Command { get, .. } => {
+                let output = shell(get)
+                    .stderr(Stdio::inherit())
+                    .output()
+                    .with_context(|| format!("running: {get}"))?;
+
+                if !output.status.success() {
+                    anyhow::bail!("Credential command failed ({}): {get}", output.status);
+                }
+
+                let value = String::from_utf8(output.stdout)?.trim().to_string();
+                Ok(Some(value).filter(|value| !value.is_empty()))
+            }
+            Self::Env => Ok(None),
+        }
+    }
+
+    pub fn save(&self, value: &str) -> Result<()> {
+        match self {
+            Self::Keyring => keyring_entry()?
+                .set_password(value)
+                .context("saving to keyring"),
+            Self::File { path, key } => {
+                let cipher = cipher(key)?;
+                let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+                let ciphertext = cipher
+                    .encrypt(&nonce, value.as_bytes())
+                    .map_err(|_| anyhow::anyhow!("Unable to encrypt credentials"))?;
+
+                write_private(path, &[nonce.as_slice(), &ciphertext].concat())
+            }
+            Self::Command { set: Some(set), .. } => {
+                let mut child = shell(set)
+                    .stdin(Stdio::piped())
+                    .spawn()
+                    .with_context(|| format!("running: {set}"))?;
+
+                child
+                    .stdin
+                    .take()
+                    .context("credential command stdin")?
+                    .write_all(value.as_bytes())?;
+
+                let status = child.wait()?;
+                if !status.success() {
+                    anyhow::bail!("Credential command failed ({status}): {set}");
+                }
+
+                Ok(())
+            }
+            Self::Command { set: None, .. } => {
+                anyhow::bail!("Set SG_CREDENTIAL_STORE_COMMAND to save credentials")
+            }
+            Self::Env => Ok(()),
+        }
+    }
+}
+
+fn keyring_entry() -> Result<keyring::Entry> {
+    let username = whoami::username();
+    keyring::Entry::new("cody-access-token", &username).context("getting keyring entry")
+}
+
+/// Load the key for the credentials file, creating it the first time.
+fn cipher(key: &Path) -> Result<ChaCha20Poly1305> {
+    let bytes = match std::fs::read(key) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            let bytes = ChaCha20Poly1305::generate_key(&mut OsRng).to_vec();
+            write_private(key, &bytes)?;
+            bytes
+        }
+        Err(err) => return Err(err).context("reading credentials key"),
+    };
+
+    if bytes.len() != 32 {
+        anyhow::bail!("Credentials key is corrupted: {}", key.display());
+    }
+
+    Ok(ChaCha20Poly1305::new(Key::from_slice(&bytes)))
+}
+
+/// Write a file that only the current user can read.
+fn write_private(path: &Path, contents: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(contents))
+        .with_context(|| format!("writing {}", path.display()))
+}
+
+pub(super) fn shell(command: &str) -> Command {
+    if cfg!(windows) {
+        let mut cmd = Command::new("cmd");
+        cmd.args(["/C", command]);
+        cmd
+    } else {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", command]);
+        cmd
+    }
+}
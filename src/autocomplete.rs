@@ -0,0 +1,458 @@
+//! Inline code completions, shown as ghost text while typing.
+//!
+//! The code around the cursor is sent as a fill-in-the-middle prompt: Cody is shown the code
+//! before and after the cursor, and continues the line the cursor is on until it reaches a stop
+//! sequence. It uses the instance's completion model unless another is asked for. One candidate
+//! is requested unless the editor asks for more, which are requested at once at different
+//! temperatures. Each is cleaned up before it is shown:
+//!
+//! - multi-line completions are cut off where they leave the block the cursor is in,
+//! - lines that are already after the cursor are removed,
+//! - completions with mismatched brackets or unterminated strings are dropped.
+
+use {
+    crate::{auth, complete_messages, get_cody_models},
+    anyhow::Result,
+    lsp_types::{Position, Range},
+    once_cell::sync::Lazy,
+    serde::{Deserialize, Serialize},
+    sg_gql::completions_stream::estimate_tokens,
+    sg_types::{CodyMessage, CodySpeaker, CompletionParameters, ModelPurpose},
+    std::{collections::HashMap, sync::Mutex},
+};
+
+/// Tokens of code before and after the cursor sent with the prompt.
+const PREFIX_TOKENS: usize = 1500;
+const SUFFIX_TOKENS: usize = 500;
+
+/// Short completions come back faster, and ghost text longer than this is rarely accepted.
+const MAX_TOKENS: i64 = 256;
+
+/// Each candidate is sampled at a different temperature, so they aren't all the same. A single
+/// candidate uses the first.
+const TEMPERATURES: &[f64] = &[0.2, 0.5, 0.8];
+
+const OPEN_TAG: &str = "<CODE5711>";
+const CLOSE_TAG: &str = "</CODE5711>";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AutocompleteRequest {
+    /// Everything in the document before the cursor.
+    pub prefix: String,
+
+    /// Everything in the document after the cursor.
+    pub suffix: String,
+
+    pub path: Option<String>,
+    pub language: Option<String>,
+
+    /// How many candidates to request, up to one for each of [`TEMPERATURES`]. One when not set.
+    pub candidates: Option<usize>,
+
+    /// The instance's completion model (see [`completion_model`]) when not set.
+    pub model: Option<String>,
+}
+
+/// Text to insert at the cursor, replacing `range`. Lines are 0-indexed and characters are byte
+/// columns, as Neovim counts them.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    pub text: String,
+    pub range: Range,
+}
+
+/// The model the current instance uses for code completions, rather than the chat model it uses
+/// by default. Remembered per endpoint; if the models can't be listed, the instance's default is
+/// used and they are asked for again next time.
+async fn completion_model() -> Option<String> {
+    static MODELS: Lazy<Mutex<HashMap<String, Option<String>>>> = Lazy::new(Default::default);
+
+    let endpoint = auth::get_endpoint().ok()?;
+    if let Some(model) = MODELS.lock().unwrap().get(&endpoint) {
+        return model.clone();
+    }
+
+    let models = get_cody_models().await.ok()?;
+    let model = models
+        .into_iter()
+        .find(|model| model.purpose == ModelPurpose::Completion)
+        .map(|model| model.id);
+
+    MODELS.lock().unwrap().insert(endpoint, model.clone());
+    model
+}
+
+/// Where the cursor is, and what is around it.
+#[derive(Debug, Clone)]
+struct Cursor<'a> {
+    position: Position,
+
+    /// The line before and after the cursor.
+    line_prefix: &'a str,
+    line_suffix: &'a str,
+
+    /// The lines after the cursor's line.
+    next_lines: Vec<&'a str>,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(prefix: &'a str, suffix: &'a str) -> Self {
+        let line_prefix = prefix.rsplit('\n').next().unwrap_or_default();
+        let (line_suffix, rest) = suffix.split_once('\n').unwrap_or((suffix, ""));
+
+        Self {
+            position: Position::new(
+                prefix.matches('\n').count() as u32,
+                line_prefix.len() as u32,
+            ),
+            line_prefix,
+            line_suffix,
+            next_lines: rest.lines().collect(),
+        }
+    }
+
+    fn indent(&self) -> usize {
+        indent(self.line_prefix)
+    }
+
+    /// Only the rest of the line is completed when there is already code after the cursor.
+    fn multiline(&self) -> bool {
+        self.line_suffix.trim().is_empty()
+    }
+}
+
+/// Request completions for the cursor between `request.prefix` and `request.suffix`. Candidates
+/// that fail or are dropped while cleaning up are left out, so the result may be empty.
+pub async fn complete(request: AutocompleteRequest) -> Result<Vec<Candidate>> {
+    let cursor = Cursor::new(&request.prefix, &request.suffix);
+    let messages = prompt(&request, &cursor);
+
+    let model = match &request.model {
+        Some(model) => Some(model.clone()),
+        None => completion_model().await,
+    };
+
+    let count = request.candidates.unwrap_or(1).clamp(1, TEMPERATURES.len());
+    let completions = futures::future::join_all(TEMPERATURES[..count].iter().map(|temperature| {
+        let parameters = CompletionParameters {
+            model: model.clone(),
+            temperature: Some(*temperature),
+            max_tokens: Some(MAX_TOKENS),
+            stop_sequences: stop_sequences(&cursor),
+            ..Default::default()
+        };
+
+        complete_messages(messages.clone(), parameters)
+    }))
+    .await;
+
+    let mut candidates: Vec<Candidate> = vec![];
+    let mut error = None;
+    for completion in completions {
+        match completion {
+            Ok(completion) => {
+                if let Some(candidate) = process(&completion, &cursor) {
+                    if !candidates.iter().any(|other| other.text == candidate.text) {
+                        candidates.push(candidate);
+                    }
+                }
+            }
+            Err(err) => error = Some(err),
+        }
+    }
+
+    match error {
+        Some(err) if candidates.is_empty() => Err(err),
+        _ => Ok(candidates),
+    }
+}
+
+/// The code around the cursor, with the cursor marked by empty tags. Cody's reply starts with
+/// the line the cursor is on, so the completion continues it.
+fn prompt(request: &AutocompleteRequest, cursor: &Cursor) -> Vec<CodyMessage> {
+    let before = request
+        .prefix
+        .strip_suffix(cursor.line_prefix)
+        .unwrap_or_default();
+    let before = tail(before, PREFIX_TOKENS);
+    let after = head(&request.suffix, SUFFIX_TOKENS);
+
+    let file = match (&request.path, &request.language) {
+        (Some(path), _) => format!(" from `{path}`"),
+        (None, Some(language)) => format!(" in {language}"),
+        (None, None) => String::new(),
+    };
+
+    let messages = [
+        (
+            CodySpeaker::Human,
+            format!(
+                "You are a code completion AI. You complete the code between {OPEN_TAG} tags so \
+                 it fits with the code around it, following its style, and reply with code only."
+            ),
+        ),
+        (
+            CodySpeaker::Assistant,
+            "I complete code so it fits with the code around it, and only reply with code."
+                .to_string(),
+        ),
+        (
+            CodySpeaker::Human,
+            format!(
+                "Here is the code{file}. Complete the code between the tags, without repeating \
+                 the code after them:\n```\n{before}{OPEN_TAG}{CLOSE_TAG}{after}\n```"
+            ),
+        ),
+        (
+            CodySpeaker::Assistant,
+            format!(
+                "Here is the completed code:\n{OPEN_TAG}{}",
+                cursor.line_prefix
+            ),
+        ),
+    ];
+
+    messages
+        .into_iter()
+        .map(|(speaker, text)| CodyMessage { speaker, text })
+        .collect()
+}
+
+fn stop_sequences(cursor: &Cursor) -> Vec<String> {
+    let mut stop = vec![CLOSE_TAG.to_string(), "\n\nHuman:".to_string()];
+    if !cursor.multiline() {
+        stop.push("\n".to_string());
+    }
+
+    stop
+}
+
+/// The end of `text` that fits in `tokens`, in whole lines.
+fn tail(text: &str, tokens: usize) -> &str {
+    let mut used = 0;
+    let mut start = text.len();
+    for line in text.split_inclusive('\n').rev() {
+        used += estimate_tokens(line);
+        if used > tokens {
+            break;
+        }
+        start -= line.len();
+    }
+
+    &text[start..]
+}
+
+/// The start of `text` that fits in `tokens`, in whole lines.
+fn head(text: &str, tokens: usize) -> &str {
+    let mut used = 0;
+    let mut end = 0;
+    for line in text.split_inclusive('\n') {
+        used += estimate_tokens(line);
+        if used > tokens {
+            break;
+        }
+        end += line.len();
+    }
+
+    &text[..end]
+}
+
+fn indent(line: &str) -> usize {
+    line.len() - line.trim_start().len()
+}
+
+/// Clean up a completion, or drop it if nothing useful is left.
+fn process(completion: &str, cursor: &Cursor) -> Option<Candidate> {
+    let completion = completion
+        .split(CLOSE_TAG)
+        .next()
+        .unwrap_or_default()
+        .trim_end();
+
+    let lines = match cursor.multiline() {
+        true => trim_to_block(completion.lines().collect(), cursor),
+        false => completion.lines().take(1).collect(),
+    };
+    let lines = dedupe_suffix(lines, cursor);
+
+    let text = lines.join("\n");
+    if text.trim().is_empty() || !is_valid(&text, lines.len() > 1) {
+        return None;
+    }
+
+    // When the completion ends with what is already after the cursor, it replaces it rather than
+    // repeating it
+    let line_suffix = cursor.line_suffix.trim_end();
+    let mut end = cursor.position;
+    if lines.len() == 1 && !line_suffix.is_empty() && text.ends_with(line_suffix) {
+        end.character += cursor.line_suffix.len() as u32;
+    }
+
+    Some(Candidate {
+        text,
+        range: Range::new(cursor.position, end),
+    })
+}
+
+/// Cut the completion where it leaves the block the cursor is in: at a line indented less than
+/// the cursor's line, or one that closes the block.
+fn trim_to_block<'a>(lines: Vec<&'a str>, cursor: &Cursor) -> Vec<&'a str> {
+    let block_indent = cursor.indent();
+    let mut depth = bracket_depth(cursor.line_prefix).max(0);
+
+    let mut kept = vec![];
+    for (i, line) in lines.into_iter().enumerate() {
+        if i > 0 && !line.trim().is_empty() {
+            let closes_block = depth <= 0 && line.trim_start().starts_with(['}', ')', ']']);
+            if indent(line) < block_indent || (indent(line) == block_indent && closes_block) {
+                break;
+            }
+        }
+
+        depth += bracket_depth(line);
+        kept.push(line);
+    }
+
+    // A completion that ends by opening a block would leave it empty
+    while kept.len() > 1 && kept.last().is_some_and(|line| line.trim().is_empty()) {
+        kept.pop();
+    }
+
+    kept
+}
+
+/// Remove lines at the end of the completion that are the lines already after the cursor.
+fn dedupe_suffix<'a>(mut lines: Vec<&'a str>, cursor: &Cursor) -> Vec<&'a str> {
+    let next = cursor
+        .next_lines
+        .iter()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>();
+
+    let longest = (1..lines.len().min(next.len() + 1))
+        .rev()
+        .find(|&count| {
+            let end = &lines[lines.len() - count..];
+            end.iter()
+                .map(|line| line.trim())
+                .eq(next[..count].iter().copied())
+        })
+        .unwrap_or(0);
+
+    lines.truncate(lines.len() - longest);
+    lines
+}
+
+/// How many more brackets `line` opens than closes, outside of strings.
+fn bracket_depth(line: &str) -> i32 {
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for c in line.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = in_string,
+            '"' => in_string = !in_string,
+            '(' | '[' | '{' if !in_string => depth += 1,
+            ')' | ']' | '}' if !in_string => depth -= 1,
+            _ => {}
+        }
+    }
+
+    depth
+}
+
+/// Whether `text` looks like code that could be inserted: brackets close in the order they were
+/// opened, and strings end on the line they start. Closing brackets that were opened before the
+/// cursor are fine. A multi-line completion must close what it opens, or it is unfinished.
+fn is_valid(text: &str, multiline: bool) -> bool {
+    let mut open = vec![];
+
+    for line in text.lines() {
+        let mut in_string = false;
+        let mut escaped = false;
+
+        for c in line.chars() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = in_string,
+                '"' => in_string = !in_string,
+                _ if in_string => {}
+                '(' | '[' | '{' => open.push(c),
+                ')' | ']' | '}' => {
+                    let expected = match c {
+                        ')' => '(',
+                        ']' => '[',
+                        _ => '{',
+                    };
+
+                    match open.pop() {
+                        Some(opened) if opened != expected => return false,
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if in_string {
+            return false;
+        }
+    }
+
+    !multiline || open.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(completion: &str, prefix: &str, suffix: &str) -> Option<Candidate> {
+        process(completion, &Cursor::new(prefix, suffix))
+    }
+
+    #[test]
+    fn multiline_completions_stay_in_their_block() {
+        let prefix = "fn main() {\n    if ready {\n        ";
+        let completion = "start();\n        wait();\n    }\n    stop();\n}";
+
+        let candidate = candidate(completion, prefix, "\n}\n").unwrap();
+        assert_eq!(candidate.text, "start();\n        wait();");
+        assert_eq!(
+            candidate.range,
+            Range::new(Position::new(2, 8), Position::new(2, 8))
+        );
+    }
+
+    #[test]
+    fn lines_after_the_cursor_are_not_repeated() {
+        let prefix = "fn add(a: i32, b: i32) -> i32 {\n    ";
+        let completion = "let sum = a + b;\n    sum\n}";
+
+        let candidate = candidate(completion, prefix, "\n    sum\n}\n").unwrap();
+        assert_eq!(candidate.text, "let sum = a + b;");
+    }
+
+    #[test]
+    fn completions_replace_the_rest_of_the_line() {
+        let candidate = candidate("a, b)", "call(", ")").unwrap();
+
+        assert_eq!(candidate.text, "a, b)");
+        assert_eq!(
+            candidate.range,
+            Range::new(Position::new(0, 5), Position::new(0, 6))
+        );
+    }
+
+    #[test]
+    fn invalid_completions_are_dropped() {
+        assert_eq!(candidate("foo(]", "let x = ", ""), None);
+        assert_eq!(candidate("\"unterminated", "let x = ", ""), None);
+        assert_eq!(candidate("   ", "let x = ", ""), None);
+
+        assert!(is_valid("x)", false));
+        assert!(!is_valid("if x {\n    y();", true));
+    }
+}
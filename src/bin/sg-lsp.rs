@@ -38,6 +38,7 @@ mod sg_read {
 async fn main() -> Result<()> {
     // Note that  we must have our logging only write out to stderr.
     info!("starting generic LSP server");
+    sg::auth::load().await;
 
     // Create the transport. Includes the stdio (stdin and stdout) versions but this could
     // also be implemented to use sockets or HTTP.
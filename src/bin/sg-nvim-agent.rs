@@ -1,109 +1,91 @@
 use {
-    anyhow::Result,
-    jsonrpc::RPCErr,
-    reqwest::Url,
-    sg::{
-        auth::{get_access_token, get_endpoint},
-        nvim::{self, NeovimTasks, Notification},
-    },
-    std::sync::Arc,
-    tokio::{io::BufReader, sync::Mutex, task::JoinHandle},
+    anyhow::{Context, Result},
+    jsonrpc::Transport,
 };
 
+const USAGE: &str = "usage: sg-nvim-agent [--listen <socket path>] [--schema]";
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    let stdin = tokio::io::stdin();
-    let mut reader = BufReader::new(stdin);
-    let stdout = Arc::new(Mutex::new(tokio::io::stdout()));
-
-    // Initialize by letting neovim know if we have a saved token or not
-    jsonrpc::write_msg(
-        &stdout,
-        nvim::Message::notification(Notification::Initialize {
-            endpoint: Some(get_endpoint()),
-            token: get_access_token(),
-        }),
-    )
-    .await?;
-
-    let rpc_stdout = stdout.clone();
-
-    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut args = std::env::args().skip(1);
+    let listen = match args.next().as_deref() {
+        None => None,
+        Some("--listen") => Some(args.next().context(USAGE)?),
+        Some("--schema") => {
+            let schema = sg::nvim::router::router().schema();
+            println!("{}", serde_json::to_string_pretty(&schema)?);
+            return Ok(());
+        }
+        Some("--help" | "-h") => {
+            println!("{USAGE}");
+            return Ok(());
+        }
+        Some(other) => anyhow::bail!("unknown argument: {other}\n{USAGE}"),
+    };
 
-    let rpc: JoinHandle<Result<()>> = tokio::spawn(async move {
-        let stdout = rpc_stdout;
+    match listen {
+        None => sg::agent::serve(Transport::stdio()).await,
+        Some(path) => listen_on(&path).await,
+    }
+}
 
-        loop {
-            let message: Result<Option<nvim::Message>> = jsonrpc::read_msg(&mut reader).await;
-            match message {
-                Ok(Some(nvim::Message::Request(message))) => {
-                    eprintln!("Recieved a message: {message:?}");
-                    let sent = match message.respond(&tx).await {
-                        Ok(response) => jsonrpc::write_msg(&stdout, response).await,
-                        Err(err) => {
-                            jsonrpc::write_err(
-                                &stdout,
-                                RPCErr {
-                                    code: 1,
-                                    message: err.to_string(),
-                                },
-                            )
-                            .await
-                        }
-                    };
+/// Serve every editor that connects to the socket at `path`, each in its own task.
+///
+/// Connections aren't authenticated, and the agent holds the user's token, so the socket is only
+/// ever reachable by the user: it is bound inside a directory only the user can open, restricted,
+/// and only then moved to `path`.
+#[cfg(unix)]
+async fn listen_on(path: &str) -> Result<()> {
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
 
-                    if sent.is_err() {
-                        return Err(anyhow::anyhow!("Failed to send response, must be closed"));
-                    }
-                }
-                Ok(Some(message)) => {
-                    eprintln!("Somehow got a not request: {message:?}");
-                }
-                Ok(None) => eprintln!("neovim exited unexpectedly?"),
-                Err(err) => eprintln!("[sg-nvim-agent] failed to read message {err:?}"),
-            }
+    // A socket left behind by an agent that didn't shut down cleanly would make binding fail, but
+    // anything that isn't a socket is left alone
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            anyhow::bail!("Not listening on {path}: it exists and is not a socket");
         }
-    });
-
-    let notifications: JoinHandle<Result<()>> = tokio::spawn(async move {
-        while let Some(task) = rx.recv().await {
-            match task {
-                NeovimTasks::Authentication { port } => {
-                    std::thread::spawn(move || {
-                        let server = tiny_http::Server::http(format!("127.0.0.1:{port}")).unwrap();
-                        let request = server.recv().expect("to launch request");
 
-                        // Create url to parse the parameters, a bit goofy but it is what it is
-                        let url = format!("http://127.0.0.1:{}{}", port, request.url());
-                        let url = Url::parse(&url).expect("to parse URL");
+        if tokio::net::UnixStream::connect(path).await.is_ok() {
+            anyhow::bail!("Not listening on {path}: another agent is already listening there");
+        }
+    }
 
-                        if let Some((_, token)) = url.query_pairs().find(|(k, _)| k == "token") {
-                            let response = match sg::auth::set_credentials(
-                                sg::auth::CodyCredentials {
-                                    endpoint: Some("https://sourcegraph.com/".to_string()),
-                                    token: Some(token.to_string()),
-                                },
-                            ) {
-                                Ok(_) => tiny_http::Response::from_string(
-                                    "Credentials have been saved to Neovim. Restart Neovim now.",
-                                ),
-                                Err(err) => tiny_http::Response::from_string(&format!(
-                                    "Failed to save credentials - Please report this error: {}",
-                                    err
-                                )),
-                            };
+    let target = std::path::Path::new(path);
+    let private = target
+        .parent()
+        .unwrap_or(std::path::Path::new("."))
+        .join(format!(".sg-nvim-agent-{}", std::process::id()));
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&private)
+        .with_context(|| format!("creating {}", private.display()))?;
 
-                            let _ = request.respond(response);
-                        }
-                    });
-                }
-            }
-        }
+    let bound = private.join("socket");
+    let listener = tokio::net::UnixListener::bind(&bound)
+        .and_then(|listener| {
+            std::fs::set_permissions(&bound, std::fs::Permissions::from_mode(0o600))?;
+            std::fs::rename(&bound, target)?;
+            Ok(listener)
+        })
+        .with_context(|| format!("listening on {path}"));
+    let _ = std::fs::remove_file(&bound);
+    let _ = std::fs::remove_dir(&private);
+    let listener = listener?;
+    eprintln!("[sg-nvim-agent] listening on {path}");
 
-        Ok(())
-    });
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(serve_connection(Transport::from_stream(stream)));
+    }
+}
 
-    let _ = tokio::try_join!(rpc, notifications)?;
+#[cfg(not(unix))]
+async fn listen_on(path: &str) -> Result<()> {
+    anyhow::bail!("Unix sockets are not supported on this platform, can't listen on {path}")
+}
 
-    Ok(())
+async fn serve_connection(transport: Transport) {
+    if let Err(err) = sg::agent::serve(transport).await {
+        eprintln!("[sg-nvim-agent] connection failed: {err:?}");
+    }
 }
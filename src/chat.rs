@@ -0,0 +1,434 @@
+//! Multi-turn Cody conversations.
+//!
+//! Each conversation is a [`Transcript`] of alternating human and assistant messages, saved as
+//! JSON in the data directory (`~/.local/share/sg.nvim/chats` on Linux) so it survives restarts.
+//! When a message is submitted, its [context](crate::context) and as much of the history as fits
+//! in the prompt's token budget are sent with it, dropping the oldest exchanges first.
+
+use {
+    crate::{
+        cody_preamble, complete_messages,
+        context::{self, Snippet},
+        stream_messages,
+    },
+    anyhow::{Context, Result},
+    once_cell::sync::Lazy,
+    rand::{distributions::Alphanumeric, Rng},
+    serde::{Deserialize, Serialize},
+    sg_gql::completions_stream::{estimate_tokens, CompletionUsage},
+    sg_types::{CodyMessage, CodySpeaker, CompletionParameters},
+    std::{
+        collections::BTreeMap,
+        path::{Path, PathBuf},
+        sync::Mutex,
+        time::SystemTime,
+    },
+};
+
+/// Tokens of history and context sent with a message. Leaves room for the reply within the
+/// context window of the smallest models Sourcegraph offers.
+pub const PROMPT_TOKEN_BUDGET: usize = 6000;
+
+const TITLE_LENGTH: usize = 60;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Transcript {
+    pub id: String,
+    pub title: Option<String>,
+
+    /// The programming language the conversation is about, mentioned in Cody's preamble.
+    pub language: Option<String>,
+
+    pub messages: Vec<CodyMessage>,
+
+    /// Seconds since the epoch.
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TranscriptSummary {
+    pub id: String,
+    pub title: Option<String>,
+    pub messages: usize,
+    pub updated_at: u64,
+}
+
+impl Transcript {
+    pub fn new(title: Option<String>, language: Option<String>) -> Self {
+        let id = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(16)
+            .map(char::from)
+            .collect();
+        let now = now();
+
+        Self {
+            id,
+            title,
+            language,
+            messages: vec![],
+            created_at: now,
+            updated_at: now,
+        }
+    }
+
+    pub fn summary(&self) -> TranscriptSummary {
+        TranscriptSummary {
+            id: self.id.clone(),
+            title: self.title.clone(),
+            messages: self.messages.len(),
+            updated_at: self.updated_at,
+        }
+    }
+
+    /// The messages to send for `message`: the preamble, `context`, as much history as fits in
+    /// `budget` tokens, `message` itself, and an empty reply for Cody to complete.
+    pub fn prompt(&self, message: &str, context: &[Snippet], budget: usize) -> Vec<CodyMessage> {
+        let mut preamble = vec![
+            CodyMessage {
+                speaker: CodySpeaker::Human,
+                text: cody_preamble(self.language.as_deref()),
+            },
+            CodyMessage {
+                speaker: CodySpeaker::Assistant,
+                text: "Understood.".to_string(),
+            },
+        ];
+        preamble.extend(context::messages(context));
+
+        let used = preamble
+            .iter()
+            .map(|msg| estimate_tokens(&msg.text))
+            .sum::<usize>()
+            + estimate_tokens(message);
+
+        // Whole exchanges are kept, so the history always starts with a human message
+        let mut remaining = budget.saturating_sub(used);
+        let mut start = self.messages.len();
+        for exchange in self.messages.rchunks(2) {
+            let tokens = exchange
+                .iter()
+                .map(|msg| estimate_tokens(&msg.text))
+                .sum::<usize>();
+
+            if tokens > remaining || exchange[0].speaker != CodySpeaker::Human {
+                break;
+            }
+
+            remaining -= tokens;
+            start -= exchange.len();
+        }
+
+        preamble
+            .into_iter()
+            .chain(self.messages[start..].iter().cloned())
+            .chain([
+                CodyMessage {
+                    speaker: CodySpeaker::Human,
+                    text: message.to_string(),
+                },
+                CodyMessage {
+                    speaker: CodySpeaker::Assistant,
+                    text: String::new(),
+                },
+            ])
+            .collect()
+    }
+
+    fn push_exchange(&mut self, message: String, reply: String) {
+        if self.title.is_none() {
+            let title = message.lines().next().unwrap_or_default();
+            self.title = Some(title.chars().take(TITLE_LENGTH).collect());
+        }
+
+        self.messages.push(CodyMessage {
+            speaker: CodySpeaker::Human,
+            text: message,
+        });
+        self.messages.push(CodyMessage {
+            speaker: CodySpeaker::Assistant,
+            text: reply,
+        });
+        self.updated_at = now();
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default()
+}
+
+/// Transcripts on disk, one file per conversation.
+pub struct ChatStore {
+    dir: PathBuf,
+
+    /// Transcripts that have been read, by id. `None` until the directory has been read.
+    transcripts: Option<BTreeMap<String, Transcript>>,
+
+    /// Why files in the directory couldn't be read as transcripts, until they are reported.
+    skipped: Vec<String>,
+}
+
+impl ChatStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            transcripts: None,
+            skipped: vec![],
+        }
+    }
+
+    fn transcripts(&mut self) -> &mut BTreeMap<String, Transcript> {
+        let Self {
+            dir,
+            transcripts,
+            skipped,
+        } = self;
+
+        transcripts.get_or_insert_with(|| load_all(dir, skipped))
+    }
+
+    /// Files that were skipped while reading the directory, since this was last called.
+    pub fn take_skipped(&mut self) -> Vec<String> {
+        self.transcripts();
+        std::mem::take(&mut self.skipped)
+    }
+
+    pub fn get(&mut self, id: &str) -> Result<Transcript> {
+        self.transcripts()
+            .get(id)
+            .cloned()
+            .with_context(|| format!("No conversation with id {id}"))
+    }
+
+    pub fn save(&mut self, transcript: Transcript) -> Result<()> {
+        std::fs::create_dir_all(&self.dir)
+            .with_context(|| format!("creating {}", self.dir.display()))?;
+
+        let path = self.path(&transcript.id);
+        std::fs::write(&path, serde_json::to_vec_pretty(&transcript)?)
+            .with_context(|| format!("writing {}", path.display()))?;
+
+        self.transcripts().insert(transcript.id.clone(), transcript);
+        Ok(())
+    }
+
+    pub fn delete(&mut self, id: &str) -> Result<()> {
+        if self.transcripts().remove(id).is_none() {
+            anyhow::bail!("No conversation with id {id}");
+        }
+
+        match std::fs::remove_file(self.path(id)) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                Err(err).with_context(|| format!("deleting conversation {id}"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Every conversation, most recently updated first.
+    pub fn list(&mut self) -> Vec<TranscriptSummary> {
+        let mut summaries = self
+            .transcripts()
+            .values()
+            .map(Transcript::summary)
+            .collect::<Vec<_>>();
+
+        summaries.sort_by_key(|summary| std::cmp::Reverse(summary.updated_at));
+        summaries
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{id}.json"))
+    }
+}
+
+fn load_all(dir: &Path, skipped: &mut Vec<String>) -> BTreeMap<String, Transcript> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return BTreeMap::new();
+    };
+
+    entries
+        .flatten()
+        .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "json"))
+        .filter_map(|entry| {
+            let contents = std::fs::read(entry.path()).ok()?;
+            match serde_json::from_slice::<Transcript>(&contents) {
+                Ok(transcript) => Some((transcript.id.clone(), transcript)),
+                Err(err) => {
+                    skipped.push(format!(
+                        "Skipped conversation {}: {err}",
+                        entry.path().display()
+                    ));
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+static STORE: Lazy<Mutex<ChatStore>> = Lazy::new(|| {
+    let dir = dirs::data_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join("sg.nvim")
+        .join("chats");
+
+    Mutex::new(ChatStore::new(dir))
+});
+
+/// Run `f` with the store on the blocking thread pool, since it reads and writes files.
+async fn with_store<T: Send + 'static>(
+    f: impl FnOnce(&mut ChatStore) -> Result<T> + Send + 'static,
+) -> Result<T> {
+    tokio::task::spawn_blocking(move || f(&mut STORE.lock().expect("to unlock chat store")))
+        .await
+        .context("chat store task failed")?
+}
+
+pub async fn new_chat(title: Option<String>, language: Option<String>) -> Result<Transcript> {
+    let transcript = Transcript::new(title, language);
+    with_store(move |store| {
+        store.save(transcript.clone())?;
+        Ok(transcript)
+    })
+    .await
+}
+
+pub async fn get_chat(id: &str) -> Result<Transcript> {
+    let id = id.to_string();
+    with_store(move |store| store.get(&id)).await
+}
+
+/// Every conversation, and the files that had to be skipped since the last time they were listed.
+pub async fn list_chats() -> Result<(Vec<TranscriptSummary>, Vec<String>)> {
+    with_store(|store| Ok((store.list(), store.take_skipped()))).await
+}
+
+pub async fn delete_chat(id: &str) -> Result<()> {
+    let id = id.to_string();
+    with_store(move |store| store.delete(&id)).await
+}
+
+/// Send `message` in the conversation `id`, and save the exchange once Cody has replied. When
+/// `on_delta` is set, the reply is streamed to it.
+///
+/// Nothing is saved if the request fails or is cancelled, so the message can be sent again.
+pub async fn submit(
+    id: &str,
+    message: String,
+    context: &[Snippet],
+    parameters: CompletionParameters,
+    on_delta: Option<impl FnMut(&str) + Send>,
+) -> Result<(String, Option<CompletionUsage>)> {
+    let transcript = get_chat(id).await?;
+    let prompt = transcript.prompt(&message, context, PROMPT_TOKEN_BUDGET);
+
+    let (reply, usage) = match on_delta {
+        Some(on_delta) => {
+            let (reply, usage) = stream_messages(prompt, parameters, on_delta).await?;
+            (reply, Some(usage))
+        }
+        None => (complete_messages(prompt, parameters).await?, None),
+    };
+
+    // Read again, in case other messages were sent while this one was being answered
+    let mut transcript = get_chat(id).await?;
+    transcript.push_exchange(message, reply.clone());
+    with_store(move |store| store.save(transcript)).await?;
+
+    Ok((reply, usage))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transcript(exchanges: &[(&str, &str)]) -> Transcript {
+        let mut transcript = Transcript::new(None, Some("Rust".to_string()));
+        for (message, reply) in exchanges {
+            transcript.push_exchange(message.to_string(), reply.to_string());
+        }
+
+        transcript
+    }
+
+    fn texts(messages: &[CodyMessage]) -> Vec<&str> {
+        messages.iter().map(|msg| msg.text.as_str()).collect()
+    }
+
+    #[test]
+    fn prompt_includes_history_that_fits() {
+        let transcript = transcript(&[("one", "1"), ("two", "2")]);
+        let prompt = transcript.prompt("three", &[], PROMPT_TOKEN_BUDGET);
+
+        assert!(prompt[0].text.contains("in the Rust programming language"));
+        assert_eq!(texts(&prompt[2..]), ["one", "1", "two", "2", "three", ""]);
+        assert_eq!(prompt.last().unwrap().speaker, CodySpeaker::Assistant);
+    }
+
+    #[test]
+    fn prompt_drops_oldest_exchanges_first() {
+        let old = "x".repeat(400);
+        let transcript = transcript(&[(&old, "old"), ("recent", "reply")]);
+
+        let preamble =
+            estimate_tokens(&cody_preamble(Some("Rust"))) + estimate_tokens("Understood.");
+        let budget = preamble + estimate_tokens("next") + 10;
+
+        let prompt = transcript.prompt("next", &[], budget);
+        assert_eq!(texts(&prompt[2..]), ["recent", "reply", "next", ""]);
+    }
+
+    #[test]
+    fn context_goes_before_history() {
+        let transcript = transcript(&[("one", "1")]);
+        let snippet = Snippet::new(context::Source::File, None, "lib.rs", 0, "fn lib() {}");
+        let prompt = transcript.prompt("two", &[snippet], PROMPT_TOKEN_BUDGET);
+
+        assert!(prompt[2].text.contains("fn lib() {}"));
+        assert_eq!(texts(&prompt[3..]), ["Ok.", "one", "1", "two", ""]);
+    }
+
+    #[test]
+    fn titles_come_from_the_first_message() {
+        let transcript = transcript(&[("How do I\nparse JSON?", "With serde")]);
+        assert_eq!(transcript.title.as_deref(), Some("How do I"));
+    }
+
+    #[test]
+    fn store_saves_to_disk() {
+        let dir = std::env::temp_dir().join(format!("sg-chats-{}", Transcript::new(None, None).id));
+
+        let saved = transcript(&[("hello", "hi")]);
+        ChatStore::new(&dir).save(saved.clone()).unwrap();
+
+        let mut store = ChatStore::new(&dir);
+        assert_eq!(store.get(&saved.id).unwrap().messages.len(), 2);
+        assert_eq!(store.list()[0].id, saved.id);
+
+        store.delete(&saved.id).unwrap();
+        assert!(ChatStore::new(&dir).list().is_empty());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn store_reports_skipped_files() {
+        let dir = std::env::temp_dir().join(format!("sg-chats-{}", Transcript::new(None, None).id));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("broken.json"), "{").unwrap();
+
+        let mut store = ChatStore::new(&dir);
+        assert!(store.list().is_empty());
+
+        let skipped = store.take_skipped();
+        assert_eq!(skipped.len(), 1);
+        assert!(skipped[0].contains("broken.json"));
+        assert!(store.take_skipped().is_empty());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
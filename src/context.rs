@@ -0,0 +1,592 @@
+//! Context for Cody prompts.
+//!
+//! Before a question is sent, the agent collects code that may help answer it: the selection and
+//! file open in the editor, embeddings results for the repository, and keyword search hits, in
+//! that order. Lines already sent are not sent again, and snippets are added until the token
+//! budget is spent. The snippets that made it into the prompt are returned as [`Citation`]s, so
+//! the editor can show what the answer was based on.
+
+use {
+    crate::{
+        entry::link,
+        get_embeddings_context, get_repository_id, get_search,
+        nvim::{router, LogLevel},
+    },
+    anyhow::{Context, Result},
+    serde::{Deserialize, Serialize},
+    sg_gql::completions_stream::estimate_tokens,
+    sg_types::{CodyMessage, CodySpeaker, Embedding},
+    std::path::Path,
+};
+
+/// Tokens of context sent with a message, unless the editor asks for a different budget. Half
+/// of [`PROMPT_TOKEN_BUDGET`](crate::chat::PROMPT_TOKEN_BUDGET), leaving the rest for history.
+pub const CONTEXT_TOKEN_BUDGET: usize = 3000;
+
+const EMBEDDINGS_CODE_RESULTS: i64 = 8;
+const EMBEDDINGS_TEXT_RESULTS: i64 = 2;
+const SEARCH_RESULTS: usize = 10;
+const SEARCH_KEYWORDS: usize = 5;
+
+/// What the editor knows about the question being asked.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ContextOptions {
+    /// Repository to look in, like `github.com/sourcegraph/sg.nvim`. Found from the git remote of
+    /// `file` when not set.
+    #[serde(default)]
+    pub repo: Option<String>,
+
+    /// The file open in the editor.
+    #[serde(default)]
+    pub file: Option<OpenFile>,
+
+    /// Lines selected in `file`.
+    #[serde(default)]
+    pub selection: Option<LineRange>,
+
+    #[serde(default = "enabled")]
+    pub embeddings: bool,
+
+    #[serde(default = "enabled")]
+    pub search: bool,
+
+    /// Tokens of context to send, [`CONTEXT_TOKEN_BUDGET`] by default.
+    #[serde(default)]
+    pub budget: Option<usize>,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OpenFile {
+    pub path: String,
+    pub content: String,
+}
+
+/// Zero-based, inclusive lines.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineRange {
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    Selection,
+    File,
+    Embeddings,
+    Search,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Snippet {
+    pub source: Source,
+    pub repo: Option<String>,
+    pub file: String,
+
+    /// Zero-based, inclusive lines of `file`.
+    pub start: usize,
+    pub end: usize,
+
+    pub content: String,
+}
+
+/// A snippet that was sent with a prompt, without its content.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Citation {
+    pub source: Source,
+    pub repo: Option<String>,
+    pub file: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Snippet {
+    /// A snippet of `content`, which starts at line `start`.
+    pub fn new(
+        source: Source,
+        repo: Option<String>,
+        file: impl Into<String>,
+        start: usize,
+        content: impl Into<String>,
+    ) -> Self {
+        let content = content.into();
+        let lines = content.lines().count().max(1);
+
+        Self {
+            source,
+            repo,
+            file: file.into(),
+            start,
+            end: start + lines - 1,
+            content,
+        }
+    }
+
+    pub fn citation(&self) -> Citation {
+        Citation {
+            source: self.source,
+            repo: self.repo.clone(),
+            file: self.file.clone(),
+            start: self.start,
+            end: self.end,
+        }
+    }
+
+    fn same_file(&self, other: &Snippet) -> bool {
+        self.repo == other.repo && self.file == other.file
+    }
+
+    /// The parts of this snippet that aren't in any of `used`, split where lines were removed.
+    fn without(&self, used: &[Snippet]) -> Vec<Snippet> {
+        let covered = |line: usize| {
+            used.iter()
+                .any(|other| self.same_file(other) && other.start <= line && line <= other.end)
+        };
+
+        let mut parts = vec![];
+        let mut run: Vec<&str> = vec![];
+        let mut run_start = self.start;
+
+        for (line, text) in (self.start..).zip(self.content.lines()) {
+            if covered(line) {
+                self.push_part(&mut parts, run_start, &mut run);
+                run_start = line + 1;
+            } else {
+                run.push(text);
+            }
+        }
+        self.push_part(&mut parts, run_start, &mut run);
+
+        parts
+    }
+
+    fn push_part(&self, parts: &mut Vec<Snippet>, start: usize, run: &mut Vec<&str>) {
+        if run.iter().any(|line| !line.trim().is_empty()) {
+            parts.push(Snippet::new(
+                self.source,
+                self.repo.clone(),
+                self.file.clone(),
+                start,
+                run.join("\n"),
+            ));
+        }
+
+        run.clear();
+    }
+}
+
+/// Pick the snippets to send from `candidates`, most important first, without repeating lines
+/// and within `budget` tokens.
+pub fn select(candidates: Vec<Snippet>, budget: usize) -> Vec<Snippet> {
+    let mut used: Vec<Snippet> = vec![];
+    let mut remaining = budget;
+
+    for candidate in candidates {
+        for part in candidate.without(&used) {
+            let tokens = estimate_tokens(&part.content);
+            if tokens <= remaining {
+                remaining -= tokens;
+                used.push(part);
+            }
+        }
+    }
+
+    used
+}
+
+/// Messages that show Cody the snippets, to go before the question.
+pub fn messages(snippets: &[Snippet]) -> Vec<CodyMessage> {
+    snippets
+        .iter()
+        .flat_map(|snippet| {
+            let location = match &snippet.repo {
+                Some(repo) => format!("`{}` in {repo}", snippet.file),
+                None => format!("`{}`", snippet.file),
+            };
+
+            let intro = match snippet.source {
+                Source::Selection => format!("Here is the code I have selected in {location}"),
+                _ => format!(
+                    "Use the following code snippet from lines {}-{} of {location}",
+                    snippet.start + 1,
+                    snippet.end + 1
+                ),
+            };
+
+            [
+                CodyMessage {
+                    speaker: CodySpeaker::Human,
+                    text: format!("{intro}:\n```\n{}\n```", snippet.content),
+                },
+                CodyMessage {
+                    speaker: CodySpeaker::Assistant,
+                    text: "Ok.".to_string(),
+                },
+            ]
+        })
+        .collect()
+}
+
+/// Collect context for `query` and select what fits in the budget.
+pub async fn assemble(query: &str, options: &ContextOptions, cx: &router::Context) -> Vec<Snippet> {
+    let budget = options.budget.unwrap_or(CONTEXT_TOKEN_BUDGET);
+    select(gather(query, options, budget, cx).await, budget)
+}
+
+/// Every snippet that could be sent for `query`, most important first. Embeddings and search are
+/// best effort: if they fail, the editor is warned and the prompt is sent without them.
+async fn gather(
+    query: &str,
+    options: &ContextOptions,
+    budget: usize,
+    cx: &router::Context,
+) -> Vec<Snippet> {
+    let local = options
+        .file
+        .as_ref()
+        .and_then(|file| locate(&file.path).ok());
+    let repo = options
+        .repo
+        .clone()
+        .or_else(|| local.as_ref().map(|(repo, _)| repo.clone()));
+
+    let mut snippets = vec![];
+    if let Some(file) = &options.file {
+        // Paths inside a repository are compared with the paths in embeddings and search
+        // results, so those lines aren't sent twice
+        let (file_repo, path) = match local {
+            Some((repo, path)) => (Some(repo), path),
+            None => (None, file.path.clone()),
+        };
+
+        snippets.extend(open_file(file, &path, file_repo, options.selection, budget));
+    }
+
+    let Some(repo) = repo else {
+        return snippets;
+    };
+
+    let (embeddings, search) = futures::join!(
+        async {
+            match options.embeddings {
+                true => embeddings(&repo, query).await,
+                false => Ok(vec![]),
+            }
+        },
+        async {
+            match options.search {
+                true => search(&repo, query).await,
+                false => Ok(vec![]),
+            }
+        },
+    );
+
+    for (source, result) in [("embeddings", embeddings), ("search", search)] {
+        match result {
+            Ok(results) => snippets.extend(results),
+            Err(err) => cx.log(
+                LogLevel::Warn,
+                format!("No {source} context for {repo}: {err:#}"),
+            ),
+        }
+    }
+
+    snippets
+}
+
+/// The repository `path` is in, and the path relative to its root.
+fn locate(path: &str) -> Result<(String, String)> {
+    let repo = link::repo_from_path(path)?;
+    let name = link::get_repo_name(&repo)?;
+    let root = repo.work_dir().context("Working directory")?;
+
+    let relative = Path::new(path).strip_prefix(root)?;
+    Ok((name, relative.to_string_lossy().into_owned()))
+}
+
+/// The selection (cut short if it doesn't fit in `budget`), then as much of the file around it as
+/// fits in half of `budget`.
+fn open_file(
+    file: &OpenFile,
+    path: &str,
+    repo: Option<String>,
+    selection: Option<LineRange>,
+    budget: usize,
+) -> Vec<Snippet> {
+    let lines = file.content.lines().collect::<Vec<_>>();
+    if lines.is_empty() {
+        return vec![];
+    }
+
+    let last = lines.len() - 1;
+    let selection = selection.map(|range| {
+        truncate(
+            &lines,
+            LineRange {
+                start: range.start.min(last),
+                end: range.end.clamp(range.start.min(last), last),
+            },
+            budget,
+        )
+    });
+
+    let mut snippets = vec![];
+    if let Some((range, content)) = &selection {
+        snippets.push(Snippet::new(
+            Source::Selection,
+            repo.clone(),
+            path,
+            range.start,
+            content.clone(),
+        ));
+    }
+
+    let range = window(
+        &lines,
+        selection.map_or(LineRange { start: 0, end: 0 }, |(range, _)| range),
+        budget / 2,
+    );
+    snippets.push(Snippet::new(
+        Source::File,
+        repo,
+        path,
+        range.start,
+        lines[range.start..=range.end].join("\n"),
+    ));
+
+    snippets
+}
+
+/// The lines of `range`, without the lines at the end that don't fit in `max_tokens`, so that a
+/// large selection is sent cut short rather than not at all. A first line that doesn't fit on its
+/// own is cut short too.
+fn truncate(lines: &[&str], range: LineRange, max_tokens: usize) -> (LineRange, String) {
+    let mut chars = lines[range.start].chars().count();
+    let mut end = range.start;
+
+    while end < range.end {
+        let next = chars + 1 + lines[end + 1].chars().count();
+        if next.div_ceil(4) > max_tokens {
+            break;
+        }
+
+        chars = next;
+        end += 1;
+    }
+
+    let mut content = lines[range.start..=end].join("\n");
+    if estimate_tokens(&content) > max_tokens {
+        content = content.chars().take(max_tokens * 4).collect();
+    }
+
+    (LineRange { end, ..range }, content)
+}
+
+/// Grow `range` a line at a time on each side, while it fits in `max_tokens`.
+fn window(lines: &[&str], range: LineRange, max_tokens: usize) -> LineRange {
+    let line_tokens = |line: &str| estimate_tokens(line) + 1;
+
+    let LineRange { mut start, mut end } = range;
+    let mut tokens = lines[start..=end]
+        .iter()
+        .map(|line| line_tokens(line))
+        .sum::<usize>();
+
+    loop {
+        let mut grew = false;
+
+        if start > 0 && tokens + line_tokens(lines[start - 1]) <= max_tokens {
+            start -= 1;
+            tokens += line_tokens(lines[start]);
+            grew = true;
+        }
+
+        if end + 1 < lines.len() && tokens + line_tokens(lines[end + 1]) <= max_tokens {
+            end += 1;
+            tokens += line_tokens(lines[end]);
+            grew = true;
+        }
+
+        if !grew {
+            return LineRange { start, end };
+        }
+    }
+}
+
+async fn embeddings(repo: &str, query: &str) -> Result<Vec<Snippet>> {
+    let id = get_repository_id(repo.to_string()).await?;
+    let embeddings = get_embeddings_context(
+        id,
+        query.to_string(),
+        EMBEDDINGS_CODE_RESULTS,
+        EMBEDDINGS_TEXT_RESULTS,
+    )
+    .await?;
+
+    Ok(embeddings
+        .into_iter()
+        .map(|embedding| match embedding {
+            Embedding::Code {
+                repo,
+                file,
+                start,
+                content,
+                ..
+            }
+            | Embedding::Text {
+                repo,
+                file,
+                start,
+                content,
+                ..
+            } => Snippet::new(Source::Embeddings, Some(repo), file, start, content),
+        })
+        .collect())
+}
+
+async fn search(repo: &str, query: &str) -> Result<Vec<Snippet>> {
+    let keywords = keywords(query);
+    if keywords.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let query = format!(
+        "repo:^{}$ ({}) count:{SEARCH_RESULTS}",
+        regex::escape(repo),
+        keywords.join(" OR ")
+    );
+
+    Ok(get_search(query)
+        .await?
+        .into_iter()
+        .map(|result| {
+            Snippet::new(
+                Source::Search,
+                Some(result.repo),
+                result.file,
+                result.line,
+                result.preview,
+            )
+        })
+        .collect())
+}
+
+/// Words from `query` worth searching for: identifiers and longer words, but not common ones.
+fn keywords(query: &str) -> Vec<String> {
+    const COMMON: &[&str] = &[
+        "the", "and", "for", "this", "that", "with", "what", "does", "how", "why", "when", "where",
+        "which", "can", "you", "are", "from", "into", "code", "function", "file", "explain",
+        "please", "should", "would", "could", "there", "here", "about", "make", "use", "used",
+    ];
+
+    let mut keywords: Vec<String> = vec![];
+    for word in query.split(|c: char| !(c.is_alphanumeric() || c == '_')) {
+        let lower = word.to_lowercase();
+        if word.chars().count() < 3 || COMMON.contains(&lower.as_str()) {
+            continue;
+        }
+
+        if !keywords
+            .iter()
+            .any(|keyword| keyword.to_lowercase() == lower)
+        {
+            keywords.push(word.to_string());
+        }
+    }
+
+    keywords.truncate(SEARCH_KEYWORDS);
+    keywords
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snippet(source: Source, file: &str, start: usize, content: &str) -> Snippet {
+        Snippet::new(
+            source,
+            Some("github.com/a/b".to_string()),
+            file,
+            start,
+            content,
+        )
+    }
+
+    #[test]
+    fn overlapping_lines_are_sent_once() {
+        let selected = snippet(Source::Selection, "lib.rs", 1, "b");
+        let file = snippet(Source::File, "lib.rs", 0, "a\nb\nc");
+        let hit = snippet(Source::Search, "lib.rs", 2, "c");
+        let other = snippet(Source::Search, "main.rs", 2, "c");
+
+        let used = select(vec![selected, file, hit, other], CONTEXT_TOKEN_BUDGET);
+        let parts = used
+            .iter()
+            .map(|s| {
+                (
+                    s.source,
+                    s.file.as_str(),
+                    s.start,
+                    s.end,
+                    s.content.as_str(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            parts,
+            [
+                (Source::Selection, "lib.rs", 1, 1, "b"),
+                (Source::File, "lib.rs", 0, 0, "a"),
+                (Source::File, "lib.rs", 2, 2, "c"),
+                (Source::Search, "main.rs", 2, 2, "c"),
+            ]
+        );
+    }
+
+    #[test]
+    fn snippets_that_dont_fit_are_skipped() {
+        let big = snippet(Source::Embeddings, "big.rs", 0, &"x".repeat(400));
+        let small = snippet(Source::Search, "small.rs", 0, "fn small() {}");
+
+        let used = select(vec![big, small.clone()], 50);
+        assert_eq!(used, [small]);
+    }
+
+    #[test]
+    fn large_selections_are_cut_short() {
+        let content = (0..100).map(|i| format!("line {i:02}")).collect::<Vec<_>>();
+        let file = OpenFile {
+            path: "lib.rs".to_string(),
+            content: content.join("\n"),
+        };
+
+        let selection = LineRange { start: 10, end: 90 };
+        let snippets = open_file(&file, "lib.rs", None, Some(selection), 20);
+        let used = select(snippets, 20);
+
+        assert_eq!(used[0].source, Source::Selection);
+        assert_eq!((used[0].start, used[0].end), (10, 19));
+        assert!(estimate_tokens(&used[0].content) <= 20);
+    }
+
+    #[test]
+    fn file_window_grows_around_the_selection() {
+        let lines = (0..100).map(|_| "abcdefg").collect::<Vec<_>>();
+        let range = window(&lines, LineRange { start: 50, end: 51 }, 30);
+
+        assert_eq!(range, LineRange { start: 46, end: 55 });
+    }
+
+    #[test]
+    fn keywords_skip_common_words() {
+        assert_eq!(
+            keywords("How does the Router decode params for a method? router"),
+            ["Router", "decode", "params", "method"]
+        );
+    }
+}
@@ -1,5 +1,6 @@
+pub use uri::{EntryError, EntryUri, Target};
 use {
-    crate::{get_path_info, normalize_url, PathInfo},
+    crate::{auth, get_path_info, normalize_url, PathInfo},
     anyhow::{Context, Result},
     regex::Regex,
     serde::{Deserialize, Serialize},
@@ -21,38 +22,49 @@ impl Entry {
             return Self::from_local_path(uri).await;
         }
 
-        let uri = normalize_url(uri);
-        let (remote_with_commit, path) = match uri.split_once("/-/") {
-            Some(split) => split,
-            None => {
-                let uri = uri.to_string();
-                let (remote, oid) = uri.split_once('@').unwrap_or((&uri, "HEAD"));
+        let EntryUri {
+            remote,
+            revision,
+            target,
+            position,
+        } = EntryUri::parse(&normalize_url(uri))?;
 
-                // Handle the repo case here.
+        let path = match target {
+            Target::Repo => {
+                return Ok(Self::Repo(Repo {
+                    remote: remote.into(),
+                    oid: revision.unwrap_or_else(|| "HEAD".to_string()).into(),
+                }))
+            }
+            Target::Commit(oid) | Target::Compare { head: oid, .. } => {
                 return Ok(Self::Repo(Repo {
-                    remote: remote.to_string().into(),
-                    oid: oid.to_string().into(),
-                }));
+                    remote: remote.into(),
+                    oid: oid.into(),
+                }))
+            }
+            Target::Path(path) | Target::Blob(path) | Target::Tree(path) | Target::Raw(path) => {
+                path
             }
         };
 
-        if path.is_empty() {
-            todo!("Handled repos, not files")
-        }
-
-        let (remote, commit) = remote_with_commit
-            .split_once('@')
-            .unwrap_or((remote_with_commit, "HEAD"));
-
-        let prefix_regex = Regex::new("^(blob|tree)/")?;
-        let path = prefix_regex.replace(path, "").to_string();
-
-        // TODO: Not sure if you can have question marks in filepaths for github...
-        //      Will need to test that out later
-        let (path, _) = path.split_once('?').unwrap_or((&path, ""));
-
-        let info = get_path_info(remote.to_string(), commit.to_string(), path.to_string()).await?;
-        Self::from_info(info)
+        // A URL copied from an instance is resolved on that instance, even if the host of the
+        // repository is routed elsewhere.
+        let requested = revision.clone().unwrap_or_else(|| "HEAD".to_string());
+        let info = auth::with_profile(
+            auth::profile_for_url(uri),
+            get_path_info(remote, requested, path),
+        )
+        .await?;
+
+        Ok(match Self::from_info(info)? {
+            Self::File(file) => Self::File(File {
+                position: position.unwrap_or(file.position),
+                revision,
+                ..file
+            }),
+            Self::Directory(dir) => Self::Directory(Directory { revision, ..dir }),
+            entry => entry,
+        })
     }
 
     pub async fn from_local_path(path: &str) -> Result<Self> {
@@ -88,35 +100,16 @@ impl Entry {
             Ok(Self::Directory(Directory {
                 remote: remote.parse()?,
                 oid: oid.parse()?,
+                revision: None,
                 path,
             }))
         } else {
-            // let position = {
-            //     // TODO: We could probably handle a few more cases here :)
-            //     let arg_split: Vec<&str> = args.split(':').collect();
-            //
-            //     if arg_split.len() == 2 {
-            //         Position {
-            //             line: Some(arg_split[0][1..].parse().unwrap_or(1)),
-            //             col: Some(arg_split[1].parse().unwrap_or(1)),
-            //         }
-            //     } else if arg_split.len() == 1 {
-            //         match arg_split[0][1..].parse() {
-            //             Ok(val) => Position {
-            //                 line: Some(val),
-            //                 col: None,
-            //             },
-            //             Err(_) => Position::default(),
-            //         }
-            //     } else {
-            //         Position::default()
-            //     }
-            // };
             let position = Position::default();
 
             Ok(Self::File(File {
                 remote: remote.parse()?,
                 oid: oid.parse()?,
+                revision: None,
                 path,
                 position,
             }))
@@ -146,38 +139,62 @@ impl std::convert::TryFrom<Entry> for lsp_types::Location {
     fn try_from(value: Entry) -> Result<Self, Self::Error> {
         use lsp_types::Url;
 
-        let position = match value.position() {
-            Some(Position {
-                line: Some(line),
-                col: Some(col),
-            }) => lsp_types::Position::new(line as u32, col as u32),
-            Some(Position {
-                line: Some(line),
-                col: None,
-            }) => lsp_types::Position::new(line as u32, 0),
-            _ => lsp_types::Position::default(),
+        let range = match value.position() {
+            Some(position) => position.range(),
+            None => lsp_types::Range::default(),
         };
 
         Ok(Self {
             uri: Url::parse(&value.bufname())?,
-            range: lsp_types::Range {
-                start: position,
-                end: position,
-            },
+            range,
         })
     }
 }
 
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+/// A position (or range) within a file, as found in `?L10:5-20:3` or `#L10` suffixes.
+///
+/// Lines and columns are 1-indexed, the same as they are displayed in Sourcegraph URLs.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Position {
     pub line: Option<usize>,
     pub col: Option<usize>,
+    pub end_line: Option<usize>,
+    pub end_col: Option<usize>,
 }
 
-fn make_bufname(remote: &Remote, oid: &OID, path: Option<&str>) -> String {
-    match path {
-        Some(path) => format!("sg://{}@{}/-/{}", remote.shortened(), oid.shortened(), path),
-        None => format!("sg://{}@{}", remote.shortened(), oid.shortened()),
+impl Position {
+    /// Parse the query or fragment of a URL, looking for a `L<line>[:<col>][-<line>[:<col>]]`
+    /// component. The GitHub style `L10-L20` form is accepted as well.
+    pub fn parse(query: &str) -> Option<Self> {
+        let re = Regex::new(r"^L(\d+)(?::(\d+))?(?:-L?(\d+)(?::(\d+))?)?$").ok()?;
+
+        query.split('&').find_map(|part| {
+            let captures = re.captures(part)?;
+            let number = |idx| captures.get(idx).and_then(|m| m.as_str().parse().ok());
+
+            Some(Self {
+                line: number(1),
+                col: number(2),
+                end_line: number(3),
+                end_col: number(4),
+            })
+        })
+    }
+
+    /// Convert to a zero-indexed LSP range, so `L10:5` starts at line 9, character 4. A missing
+    /// line or column is the first one, and a missing end is treated as the start position.
+    pub fn range(&self) -> lsp_types::Range {
+        let zero_indexed = |value: Option<usize>| value.unwrap_or(1).saturating_sub(1) as u32;
+
+        let start = lsp_types::Position::new(zero_indexed(self.line), zero_indexed(self.col));
+        let end = match self.end_line {
+            Some(end_line) => {
+                lsp_types::Position::new(zero_indexed(Some(end_line)), zero_indexed(self.end_col))
+            }
+            None => start,
+        };
+
+        lsp_types::Range { start, end }
     }
 }
 
@@ -185,13 +202,21 @@ fn make_bufname(remote: &Remote, oid: &OID, path: Option<&str>) -> String {
 pub struct File {
     pub remote: Remote,
     pub oid: OID,
+    /// The revision the file was requested at (e.g. a branch name), if one was given.
+    #[serde(default)]
+    pub revision: Option<String>,
     pub path: String,
     pub position: Position,
 }
 
 impl File {
     pub fn bufname(&self) -> String {
-        make_bufname(&self.remote, &self.oid, Some(&self.path))
+        bufname::make_bufname(
+            &self.remote,
+            &self.oid,
+            self.revision.as_deref(),
+            Some(bufname::BufnamePath::Blob(&self.path)),
+        )
     }
 }
 
@@ -199,12 +224,20 @@ impl File {
 pub struct Directory {
     pub remote: Remote,
     pub oid: OID,
+    /// The revision the directory was requested at (e.g. a branch name), if one was given.
+    #[serde(default)]
+    pub revision: Option<String>,
     pub path: String,
 }
 
 impl Directory {
     pub fn bufname(&self) -> String {
-        make_bufname(&self.remote, &self.oid, Some(&self.path))
+        bufname::make_bufname(
+            &self.remote,
+            &self.oid,
+            self.revision.as_deref(),
+            Some(bufname::BufnamePath::Tree(&self.path)),
+        )
     }
 }
 
@@ -216,7 +249,143 @@ pub struct Repo {
 
 impl Repo {
     fn bufname(&self) -> String {
-        make_bufname(&self.remote, &self.oid, None)
+        bufname::make_bufname(&self.remote, &self.oid, None, None)
+    }
+}
+
+pub mod uri {
+    use {super::Position, sg_types::encoding, std::fmt};
+
+    /// Errors that can occur while parsing an `sg://` or Sourcegraph URL into an [`EntryUri`].
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum EntryError {
+        EmptyRemote,
+        EmptyRevision,
+        MissingPath(&'static str),
+        InvalidCompare(String),
+        InvalidEncoding(String),
+    }
+
+    impl fmt::Display for EntryError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                EntryError::EmptyRemote => write!(f, "URI does not contain a repository"),
+                EntryError::EmptyRevision => write!(f, "URI contains an empty revision after '@'"),
+                EntryError::MissingPath(kind) => write!(f, "URI is missing a path after '{kind}/'"),
+                EntryError::InvalidCompare(spec) => {
+                    write!(f, "Expected 'base...head' for comparison, got: {spec:?}")
+                }
+                EntryError::InvalidEncoding(component) => {
+                    write!(
+                        f,
+                        "URI component is not valid percent-encoded UTF-8: {component:?}"
+                    )
+                }
+            }
+        }
+    }
+
+    impl std::error::Error for EntryError {}
+
+    /// What part of the repository a URI points to, i.e. everything after `/-/`.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum Target {
+        /// The root of the repository, either without `/-/` or with nothing after it.
+        Repo,
+        /// A path without a `blob/` or `tree/` prefix, as used by bufnames.
+        Path(String),
+        Blob(String),
+        Tree(String),
+        Raw(String),
+        Commit(String),
+        Compare {
+            base: String,
+            head: String,
+        },
+    }
+
+    /// A parsed entry URI, before any of it has been resolved against the Sourcegraph instance.
+    ///
+    /// The grammar (after [`crate::normalize_url`] has removed the scheme and endpoint) is:
+    ///
+    /// ```text
+    /// <remote>[@<revision>][/-/[blob/|tree/|raw/]<path> | /-/commit/<revision> | /-/compare/<base>...<head>][?<query>][#<fragment>]
+    /// ```
+    ///
+    /// Revisions may contain slashes, since they are terminated by `/-/`. Revisions and paths are
+    /// percent-decoded after splitting, so `?`, `#` and a literal `-` segment can be encoded.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct EntryUri {
+        pub remote: String,
+        pub revision: Option<String>,
+        pub target: Target,
+        pub position: Option<Position>,
+    }
+
+    impl EntryUri {
+        pub fn parse(uri: &str) -> Result<Self, EntryError> {
+            let (uri, position) = match uri.find(['?', '#']) {
+                Some(idx) => (&uri[..idx], Position::parse(&uri[idx + 1..])),
+                None => (uri, None),
+            };
+
+            let (repo, rest) = match uri.split_once("/-/") {
+                Some((repo, rest)) => (repo, rest),
+                None => (uri.strip_suffix("/-").unwrap_or(uri), ""),
+            };
+
+            let decode = |component: &str| {
+                encoding::decode(component)
+                    .map_err(|_| EntryError::InvalidEncoding(component.to_string()))
+            };
+
+            let (remote, revision) = match repo.split_once('@') {
+                Some((_, "")) => return Err(EntryError::EmptyRevision),
+                Some((remote, revision)) => (remote, Some(decode(revision)?)),
+                None => (repo, None),
+            };
+
+            let remote = remote.trim_end_matches('/');
+            if remote.is_empty() {
+                return Err(EntryError::EmptyRemote);
+            }
+
+            let rest = rest.trim_matches('/');
+            let (kind, path) = rest.split_once('/').unwrap_or((rest, ""));
+            let required = |kind| match path {
+                "" => Err(EntryError::MissingPath(kind)),
+                path => decode(path),
+            };
+
+            let target = match kind {
+                "" => Target::Repo,
+                "tree" if path.is_empty() => Target::Repo,
+                "tree" => Target::Tree(decode(path)?),
+                "blob" => Target::Blob(required("blob")?),
+                "raw" => Target::Raw(required("raw")?),
+                "commit" => Target::Commit(required("commit")?),
+                "compare" => {
+                    let spec = required("compare")?;
+                    match spec.split_once("...").or_else(|| spec.split_once("..")) {
+                        Some((base, head)) if !base.is_empty() && !head.is_empty() => {
+                            Target::Compare {
+                                base: base.to_string(),
+                                head: head.to_string(),
+                            }
+                        }
+                        _ => return Err(EntryError::InvalidCompare(spec)),
+                    }
+                }
+                _ => Target::Path(decode(rest)?),
+            };
+
+            Ok(Self {
+                remote: remote.to_string(),
+                revision,
+                target,
+                position,
+            })
+        }
     }
 }
 
@@ -277,3 +446,238 @@ pub mod link {
             .clone())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_bufname(bufname: &str) -> EntryUri {
+        let uri = bufname
+            .strip_prefix("sg://")
+            .expect("bufname to have sg:// prefix");
+        let uri = bufname::config().expand_remote(uri);
+        EntryUri::parse(&uri).expect("bufname to parse")
+    }
+
+    #[test]
+    fn positions_become_zero_indexed_ranges() {
+        let position = Position::parse("L10:5-12").unwrap();
+        assert_eq!(
+            position.range(),
+            lsp_types::Range::new(
+                lsp_types::Position::new(9, 4),
+                lsp_types::Position::new(11, 0)
+            )
+        );
+
+        assert_eq!(Position::default().range(), lsp_types::Range::default());
+    }
+
+    #[test]
+    fn file_bufname_round_trips() {
+        let file = File {
+            remote: "github.com/sourcegraph/sg.nvim".to_string().into(),
+            oid: "abcde".to_string().into(),
+            revision: None,
+            path: "lua/sg/init.lua".to_string(),
+            position: Position::default(),
+        };
+
+        let uri = parse_bufname(&file.bufname());
+        assert_eq!(uri.remote, file.remote.0);
+        assert_eq!(uri.revision.as_deref(), Some("abcde"));
+        assert_eq!(uri.target, Target::Blob(file.path.clone()));
+        assert_eq!(
+            bufname::make_bufname(
+                &uri.remote.into(),
+                &uri.revision.unwrap().into(),
+                None,
+                Some(bufname::BufnamePath::Blob(&file.path))
+            ),
+            file.bufname()
+        );
+    }
+
+    #[test]
+    fn directory_bufname_round_trips() {
+        let dir = Directory {
+            remote: "gitlab.com/gitlab-org/gitlab".to_string().into(),
+            oid: "12345".to_string().into(),
+            revision: None,
+            path: "app/models".to_string(),
+        };
+
+        let uri = parse_bufname(&dir.bufname());
+        assert_eq!(uri.remote, dir.remote.0);
+        assert_eq!(uri.revision.as_deref(), Some("12345"));
+        assert_eq!(uri.target, Target::Tree(dir.path.clone()));
+    }
+
+    #[test]
+    fn paths_named_like_kinds_round_trip() {
+        let dir = Directory {
+            remote: "github.com/x/y".to_string().into(),
+            oid: "12345".to_string().into(),
+            revision: None,
+            path: "tree".to_string(),
+        };
+
+        assert_eq!(
+            parse_bufname(&dir.bufname()).target,
+            Target::Tree("tree".to_string())
+        );
+    }
+
+    #[test]
+    fn repo_bufname_round_trips() {
+        let repo = Repo {
+            remote: "github.com/sourcegraph/sg.nvim".to_string().into(),
+            oid: "fedcb".to_string().into(),
+        };
+
+        let uri = parse_bufname(&repo.bufname());
+        assert_eq!(uri.remote, repo.remote.0);
+        assert_eq!(uri.revision.as_deref(), Some("fedcb"));
+        assert_eq!(uri.target, Target::Repo);
+        assert_eq!(
+            bufname::make_bufname(
+                &uri.remote.into(),
+                &uri.revision.unwrap().into(),
+                None,
+                None
+            ),
+            repo.bufname()
+        );
+    }
+
+    #[test]
+    fn parses_repo_roots() {
+        for uri in [
+            "github.com/x/y",
+            "github.com/x/y/",
+            "github.com/x/y/-",
+            "github.com/x/y/-/",
+            "github.com/x/y/-/tree/",
+        ] {
+            let parsed = EntryUri::parse(uri).unwrap();
+            assert_eq!(parsed.remote, "github.com/x/y", "{uri}");
+            assert_eq!(parsed.revision, None, "{uri}");
+            assert_eq!(parsed.target, Target::Repo, "{uri}");
+        }
+    }
+
+    #[test]
+    fn parses_revisions_with_slashes() {
+        let parsed = EntryUri::parse("github.com/x/y@feature/foo/-/blob/src/lib.rs").unwrap();
+        assert_eq!(parsed.revision.as_deref(), Some("feature/foo"));
+        assert_eq!(parsed.target, Target::Blob("src/lib.rs".to_string()));
+
+        let parsed = EntryUri::parse("github.com/x/y@feature/foo").unwrap();
+        assert_eq!(parsed.revision.as_deref(), Some("feature/foo"));
+        assert_eq!(parsed.target, Target::Repo);
+    }
+
+    #[test]
+    fn parses_target_kinds() {
+        let target = |uri| EntryUri::parse(uri).unwrap().target;
+
+        assert_eq!(
+            target("github.com/x/y/-/tree/src"),
+            Target::Tree("src".to_string())
+        );
+        assert_eq!(
+            target("github.com/x/y/-/raw/README.md"),
+            Target::Raw("README.md".to_string())
+        );
+        assert_eq!(
+            target("github.com/x/y/-/commit/0123456789abcdef"),
+            Target::Commit("0123456789abcdef".to_string())
+        );
+        assert_eq!(
+            target("github.com/x/y/-/compare/main...feature/foo"),
+            Target::Compare {
+                base: "main".to_string(),
+                head: "feature/foo".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn parses_positions() {
+        let parsed = EntryUri::parse("github.com/x/y/-/blob/a.rs?L10:5-20:3").unwrap();
+        assert_eq!(parsed.target, Target::Blob("a.rs".to_string()));
+        assert_eq!(
+            parsed.position,
+            Some(Position {
+                line: Some(10),
+                col: Some(5),
+                end_line: Some(20),
+                end_col: Some(3),
+            })
+        );
+
+        let parsed = EntryUri::parse("github.com/x/y/-/blob/a.rs#L7").unwrap();
+        assert_eq!(parsed.position.and_then(|p| p.line), Some(7));
+    }
+
+    #[test]
+    fn special_characters_round_trip() {
+        let revisions = ["feature/foo", "v1.2.3@beta", "a/-/b", "%#?"];
+        let paths = [
+            "src/what?.rs",
+            "docs/#readme.md",
+            "with space/100%.txt",
+            "a/-/b",
+            "raw/data.json",
+            "tree/x.rs",
+        ];
+
+        for revision in revisions {
+            for path in paths {
+                let file = File {
+                    remote: "github.com/x/y".to_string().into(),
+                    oid: revision.to_string().into(),
+                    revision: None,
+                    path: path.to_string(),
+                    position: Position::default(),
+                };
+
+                let bufname = file.bufname();
+                let uri = parse_bufname(&bufname);
+                assert_eq!(uri.remote, "github.com/x/y", "{bufname}");
+                assert_eq!(uri.revision.as_deref(), Some(revision), "{bufname}");
+                assert_eq!(uri.target, Target::Blob(path.to_string()), "{bufname}");
+                assert_eq!(uri.position, None, "{bufname}");
+            }
+        }
+    }
+
+    #[test]
+    fn decodes_sourcegraph_urls() {
+        let parsed = EntryUri::parse("github.com/x/y@feature%2Ffoo/-/blob/what%3F.rs?L3").unwrap();
+        assert_eq!(parsed.revision.as_deref(), Some("feature/foo"));
+        assert_eq!(parsed.target, Target::Blob("what?.rs".to_string()));
+        assert_eq!(parsed.position.and_then(|p| p.line), Some(3));
+    }
+
+    #[test]
+    fn reports_errors() {
+        assert_eq!(EntryUri::parse(""), Err(EntryError::EmptyRemote));
+        assert_eq!(
+            EntryUri::parse("github.com/x/y@"),
+            Err(EntryError::EmptyRevision)
+        );
+        assert_eq!(
+            EntryUri::parse("github.com/x/y/-/blob/"),
+            Err(EntryError::MissingPath("blob"))
+        );
+        assert_eq!(
+            EntryUri::parse("github.com/x/y/-/compare/main"),
+            Err(EntryError::InvalidCompare("main".to_string()))
+        );
+        assert_eq!(
+            EntryUri::parse("github.com/x/y/-/blob/%FF"),
+            Err(EntryError::InvalidEncoding("%FF".to_string()))
+        );
+    }
+}
@@ -1,55 +1,97 @@
 use {
-    anyhow::Result, graphql_client::GraphQLQuery, lsp_types::Location, once_cell::sync::Lazy,
-    regex::Regex, reqwest::Client, sg_gql::dotcom_user::UserInfo, sg_types::*,
+    anyhow::{Context, Result},
+    graphql_client::GraphQLQuery,
+    lsp_types::Location,
+    reqwest::Client,
+    sg_gql::{
+        completions_stream::{CompletionRequest, CompletionUsage},
+        dotcom_user::UserInfo,
+    },
+    sg_types::*,
     std::collections::HashMap,
 };
 
+pub mod agent;
 pub mod auth;
+pub mod autocomplete;
+pub mod chat;
+pub mod context;
 pub mod entry;
+pub mod login;
+pub mod network;
 pub mod nvim;
+pub mod permalink;
 
+/// Strip the scheme (or any configured endpoint) from a URL, leaving
+/// `<remote>[@<revision>][/-/...]`.
+///
+/// Only the prefix is rewritten: the rest of the URL is left percent-encoded, so that it can be
+/// split on `/-/`, `?` and `#` before any of the components are decoded.
 pub fn normalize_url(url: &str) -> String {
-    let re = Regex::new(r"^/").unwrap();
-
-    re.replace_all(
-        &url.to_string()
-            .replace(&auth::get_endpoint(), "")
-            .replace("//gh/", "//github.com/")
-            .replace("sg://", ""),
-        "",
-    )
-    .to_string()
+    let url = url
+        .strip_prefix("sg://")
+        .or_else(|| {
+            auth::get_endpoints()
+                .iter()
+                .find_map(|endpoint| auth::strip_endpoint(url, endpoint))
+        })
+        .unwrap_or(url)
+        .trim_start_matches('/');
+
+    bufname::config().expand_remote(url)
 }
 
 mod graphql {
     use {super::*, futures::Future, reqwest::header::HeaderMap};
 
-    static CLIENT: Lazy<Client> = Lazy::new(|| {
-        Client::builder()
-            .build()
-            .expect("to be able to create the client")
-    });
+    /// Send a request to the instance that serves `remote`, see [`auth::get_instance`].
+    ///
+    /// Tokens from a `token_command` can be revoked before they expire, so if one is rejected the
+    /// command is run again and the request is retried once.
+    pub async fn request_wrap<Q: GraphQLQuery, F, T, R>(
+        remote: Option<&str>,
+        variables: impl Into<Q::Variables>,
+        get: F,
+    ) -> Result<T>
+    where
+        Q::Variables: Clone,
+        F: Fn(Client, HeaderMap, String, Q::Variables) -> R,
+        R: Future<Output = Result<T>>,
+        T: Sized,
+    {
+        let variables = variables.into();
+        let instance = auth::get_instance(remote).await?;
+        let token_command = instance.token_command.clone();
+
+        match request_to::<Q, _, _, _>(instance, variables.clone(), &get).await {
+            Err(err) if err.is::<sg_gql::AuthError>() && token_command.is_some() => {
+                if let Some(command) = &token_command {
+                    auth::invalidate_token(command).await;
+                }
 
-    fn get_graphql_endpoint() -> String {
-        let endpoint = auth::get_endpoint();
-        format!("{endpoint}/.api/graphql")
+                request_to::<Q, _, _, _>(auth::get_instance(remote).await?, variables, &get).await
+            }
+            result => result,
+        }
     }
 
-    pub async fn request_wrap<Q: GraphQLQuery, F, T, R>(
+    pub async fn request_to<Q: GraphQLQuery, F, T, R>(
+        instance: auth::Instance,
         variables: impl Into<Q::Variables>,
         get: F,
     ) -> Result<T>
     where
-        F: Fn(&'static Client, HeaderMap, String, Q::Variables) -> R,
+        F: Fn(Client, HeaderMap, String, Q::Variables) -> R,
         R: Future<Output = Result<T>>,
         T: Sized,
     {
-        let headers = get_headers();
-        get(&CLIENT, headers, get_graphql_endpoint(), variables.into()).await
+        let headers = get_headers(instance.token.as_deref());
+        let endpoint = format!("{}/.api/graphql", instance.endpoint);
+        get(network::client(), headers, endpoint, variables.into()).await
     }
 }
 
-pub fn get_headers() -> reqwest::header::HeaderMap {
+pub fn get_headers(token: Option<&str>) -> reqwest::header::HeaderMap {
     use reqwest::header::*;
 
     let mut header_map = HeaderMap::new();
@@ -61,7 +103,7 @@ pub fn get_headers() -> reqwest::header::HeaderMap {
     );
 
     // Auth
-    if let Some(sourcegraph_access_token) = auth::get_access_token() {
+    if let Some(sourcegraph_access_token) = token {
         header_map.insert(
             AUTHORIZATION,
             HeaderValue::from_str(&format!("token {sourcegraph_access_token}"))
@@ -87,9 +129,13 @@ pub fn get_headers() -> reqwest::header::HeaderMap {
 }
 
 macro_rules! wrap_request {
+    ($path:path, remote = $remote:expr, $variables: expr) => {{
+        use $path::*;
+        graphql::request_wrap::<Query, _, _, _>(Some($remote), $variables, request).await
+    }};
     ($path:path, $variables: expr) => {{
         use $path::*;
-        graphql::request_wrap::<Query, _, _, _>($variables, request).await
+        graphql::request_wrap::<Query, _, _, _>(None, $variables, request).await
     }};
 }
 
@@ -99,6 +145,7 @@ pub async fn get_path_info(remote: String, revision: String, path: String) -> Re
 
     wrap_request!(
         sg_gql::path_info,
+        remote = &remote.clone(),
         Variables {
             name: remote,
             revision,
@@ -114,6 +161,7 @@ pub async fn get_remote_directory_contents(
 ) -> Result<Vec<PathInfo>> {
     wrap_request!(
         sg_gql::list_files,
+        remote = remote,
         Variables {
             name: remote.to_string(),
             rev: commit.to_string(),
@@ -129,6 +177,7 @@ pub async fn get_commit_hash(remote: String, revision: String) -> Result<String>
 
     wrap_request!(
         sg_gql::commit_oid,
+        remote = &remote.clone(),
         Variables {
             name: remote,
             rev: revision
@@ -136,9 +185,18 @@ pub async fn get_commit_hash(remote: String, revision: String) -> Result<String>
     )
 }
 
+pub async fn get_external_urls(remote: String) -> Result<Vec<ExternalLink>> {
+    wrap_request!(
+        sg_gql::external_urls,
+        remote = &remote.clone(),
+        Variables { name: remote }
+    )
+}
+
 pub async fn get_file_contents(remote: &str, commit: &str, path: &str) -> Result<String> {
     wrap_request!(
         sg_gql::file,
+        remote = remote,
         Variables {
             name: remote.to_string(),
             rev: commit.to_string(),
@@ -148,17 +206,26 @@ pub async fn get_file_contents(remote: &str, commit: &str, path: &str) -> Result
 }
 
 pub async fn get_sourcegraph_version() -> Result<SourcegraphVersion> {
-    auth::get_access_token().ok_or(anyhow::anyhow!("No user token. Login first"))?;
+    auth::get_instance(None)
+        .await?
+        .token
+        .ok_or(anyhow::anyhow!("No user token. Login first"))?;
 
     wrap_request!(sg_gql::sourcegraph_version, Variables {})
 }
 
+/// Context for `query` from the embeddings of `repo`. Uses `getCodyContext` when the instance has
+/// it, since `embeddingsSearch` is deprecated on newer instances.
 pub async fn get_embeddings_context(
     repo: ID,
     query: String,
     code: i64,
     text: i64,
 ) -> Result<Vec<Embedding>> {
+    if has_cody_context().await {
+        return get_cody_context(vec![repo], query, code, text).await;
+    }
+
     wrap_request!(
         sg_gql::embeddings_context,
         Variables {
@@ -170,6 +237,55 @@ pub async fn get_embeddings_context(
     )
 }
 
+/// Context for `query` from several repositories at once. Needs Sourcegraph 5.1 or later.
+pub async fn get_cody_context(
+    repos: Vec<ID>,
+    query: String,
+    code: i64,
+    text: i64,
+) -> Result<Vec<Embedding>> {
+    wrap_request!(
+        sg_gql::cody_context,
+        Variables {
+            repos,
+            query,
+            code,
+            text,
+        }
+    )
+}
+
+/// Whether the current instance has `getCodyContext`, remembered per endpoint. If the version
+/// can't be read, it is asked again next time.
+async fn has_cody_context() -> bool {
+    static SUPPORTED: once_cell::sync::Lazy<std::sync::Mutex<HashMap<String, bool>>> =
+        once_cell::sync::Lazy::new(Default::default);
+
+    let Ok(endpoint) = auth::get_endpoint() else {
+        return false;
+    };
+    if let Some(supported) = SUPPORTED.lock().unwrap().get(&endpoint) {
+        return *supported;
+    }
+
+    match get_sourcegraph_version().await {
+        Ok(version) => {
+            let supported = sg_gql::cody_context::is_supported(&version);
+            SUPPORTED.lock().unwrap().insert(endpoint, supported);
+            supported
+        }
+        Err(_) => false,
+    }
+}
+
+pub async fn get_repository_id(remote: String) -> Result<ID> {
+    wrap_request!(
+        sg_gql::repository_id,
+        remote = &remote.clone(),
+        Variables { name: remote }
+    )
+}
+
 pub async fn get_hover(uri: String, line: i64, character: i64) -> Result<String> {
     let remote_file = entry::Entry::new(&uri).await?;
     let remote_file = match remote_file {
@@ -179,6 +295,7 @@ pub async fn get_hover(uri: String, line: i64, character: i64) -> Result<String>
 
     wrap_request!(
         sg_gql::hover,
+        remote = &remote_file.remote.0.clone(),
         Variables {
             repository: remote_file.remote.0,
             revision: remote_file.oid.0,
@@ -189,21 +306,38 @@ pub async fn get_hover(uri: String, line: i64, character: i64) -> Result<String>
     )
 }
 
-pub async fn get_cody_completions(
-    text: String,
-    prefix: Option<String>,
-    temperature: Option<f64>,
-) -> Result<String> {
-    // TODO: Figure out how to deal with messages
+/// What Cody is told about itself before the conversation starts.
+pub fn cody_preamble(language: Option<&str>) -> String {
+    let task = match language {
+        Some(language) => format!(
+            "My task is to help programmers with programming tasks in the {language} programming language."
+        ),
+        None => "My task is to help programmers with programming tasks.".to_string(),
+    };
 
-    let messages = vec![
-            CodyMessage {
-                speaker: CodySpeaker::Assistant,
-                text: "I am Cody, an AI-powered coding assistant developed by Sourcegraph. I operate inside a Language Server Protocol implementation. My task is to help programmers with programming tasks in the %s programming language.
+    format!(
+        "I am Cody, an AI-powered coding assistant developed by Sourcegraph. I operate inside a Language Server Protocol implementation. {task}
     I have access to your currently open files in the editor.
     I will generate suggestions as concisely and clearly as possible.
-    I only suggest something if I am certain about my answer.".to_string(),
-            },
+    I only suggest something if I am certain about my answer."
+    )
+}
+
+/// The messages for a one-off question: the preamble, `context`, `text`, and the start of
+/// Cody's reply.
+fn cody_messages(
+    text: String,
+    prefix: Option<String>,
+    context: &[context::Snippet],
+) -> Vec<CodyMessage> {
+    let preamble = CodyMessage {
+        speaker: CodySpeaker::Assistant,
+        text: cody_preamble(None),
+    };
+
+    std::iter::once(preamble)
+        .chain(context::messages(context))
+        .chain([
             CodyMessage {
                 speaker: CodySpeaker::Human,
                 text,
@@ -212,17 +346,100 @@ pub async fn get_cody_completions(
                 speaker: CodySpeaker::Assistant,
                 text: prefix.unwrap_or("".to_string()),
             },
-        ];
+        ])
+        .collect()
+}
+
+pub async fn get_cody_completions(
+    text: String,
+    prefix: Option<String>,
+    context: &[context::Snippet],
+    parameters: CompletionParameters,
+) -> Result<String> {
+    complete_messages(cody_messages(text, prefix, context), parameters).await
+}
+
+/// Complete a conversation, which should end with the (possibly empty) start of Cody's reply.
+pub async fn complete_messages(
+    messages: Vec<CodyMessage>,
+    parameters: CompletionParameters,
+) -> Result<String> {
+    // The GraphQL API can't pick a model, stop early or take a fractional top-p, but the
+    // streaming endpoint can
+    if parameters.model.is_some()
+        || !parameters.stop_sequences.is_empty()
+        || parameters.top_p.is_some()
+    {
+        let (completion, _) = stream_messages(messages, parameters, |_| {}).await?;
+        return Ok(completion);
+    }
 
     wrap_request!(
         sg_gql::cody_completion,
         Variables {
             messages,
-            temperature,
+            parameters,
         }
     )
 }
 
+/// The models Cody can use on the current instance.
+pub async fn get_cody_models() -> Result<Vec<CodyModel>> {
+    wrap_request!(sg_gql::cody_models, Variables {})
+}
+
+/// Like [`get_cody_completions`], but calls `on_delta` with each piece of the completion as it
+/// arrives.
+pub async fn stream_cody_completions(
+    text: String,
+    prefix: Option<String>,
+    context: &[context::Snippet],
+    parameters: CompletionParameters,
+    on_delta: impl FnMut(&str) + Send,
+) -> Result<(String, CompletionUsage)> {
+    stream_messages(cody_messages(text, prefix, context), parameters, on_delta).await
+}
+
+/// Like [`complete_messages`], but calls `on_delta` with each piece of the completion as it
+/// arrives.
+pub async fn stream_messages(
+    messages: Vec<CodyMessage>,
+    parameters: CompletionParameters,
+    mut on_delta: impl FnMut(&str) + Send,
+) -> Result<(String, CompletionUsage)> {
+    let request = CompletionRequest::new(messages, parameters);
+
+    async fn stream_to(
+        instance: auth::Instance,
+        request: &CompletionRequest,
+        on_delta: &mut (dyn FnMut(&str) + Send),
+    ) -> Result<(String, CompletionUsage)> {
+        let headers = get_headers(instance.token.as_deref());
+        sg_gql::completions_stream::stream(
+            &network::client(),
+            headers,
+            &instance.endpoint,
+            request,
+            on_delta,
+        )
+        .await
+    }
+
+    // Rejected tokens are found out before anything is streamed, so retrying can't repeat text
+    let instance = auth::get_instance(None).await?;
+    let token_command = instance.token_command.clone();
+    match (
+        stream_to(instance, &request, &mut on_delta).await,
+        token_command,
+    ) {
+        (Err(err), Some(command)) if err.is::<sg_gql::AuthError>() => {
+            auth::invalidate_token(&command).await;
+            stream_to(auth::get_instance(None).await?, &request, &mut on_delta).await
+        }
+        (result, _) => result,
+    }
+}
+
 pub async fn get_definitions(
     uri: String,
     line: i64,
@@ -237,6 +454,7 @@ pub async fn get_definitions(
 
     wrap_request!(
         sg_gql::definition,
+        remote = &remote_file.remote.0.clone(),
         Variables {
             repository: remote_file.remote.0,
             revision: remote_file.oid.0,
@@ -256,6 +474,7 @@ pub async fn get_references(uri: String, line: i64, character: i64) -> Result<Ve
 
     wrap_request!(
         sg_gql::references,
+        remote = &remote_file.remote.0.clone(),
         Variables {
             repository: remote_file.remote.0,
             revision: remote_file.oid.0,
@@ -270,9 +489,23 @@ pub async fn get_search(query: String) -> Result<Vec<SearchResult>> {
     wrap_request!(sg_gql::search, Variables { query })
 }
 
+/// Check that `credentials` can log in, before they are saved. Returns the username.
+pub async fn validate_credentials(credentials: &auth::CodyCredentials) -> Result<String> {
+    let instance = auth::Instance::resolve(credentials.clone()).await;
+    let endpoint = instance.endpoint.clone();
+
+    let user = {
+        use sg_gql::enterprise_user::*;
+        graphql::request_to::<Query, _, _, _>(instance, Variables {}, request).await
+    };
+
+    user.map(|user| user.username)
+        .with_context(|| format!("Invalid credentials for {endpoint}"))
+}
+
 pub async fn get_user_info() -> Result<UserInfo> {
-    let endpoint = auth::get_endpoint();
-    let token = auth::get_access_token();
+    let endpoint = auth::get_endpoint()?;
+    let token = auth::get_access_token().await;
     match (token, endpoint.as_str()) {
         (None, _) => Err(anyhow::anyhow!("No user information. Must log in first")),
         (Some(_), "https://sourcegraph.com") => wrap_request!(sg_gql::dotcom_user, Variables {}),
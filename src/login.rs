@@ -0,0 +1,286 @@
+//! Log in through the browser.
+//!
+//! The user is sent to the token callback page of their instance, which creates an access token
+//! and redirects back to a local server with the token and the `state` nonce we generated. The
+//! nonce is checked so that only the login we started can save credentials.
+//!
+//! Only one login waits at a time, since they all listen on the same port: starting another one
+//! (or cancelling the request that started it) stops the one that is waiting.
+
+use {
+    crate::{
+        auth::{self, CodyCredentials},
+        network::Network,
+    },
+    anyhow::Result,
+    jsonrpc::Id,
+    once_cell::sync::Lazy,
+    rand::{distributions::Alphanumeric, Rng},
+    reqwest::Url,
+    std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    },
+    tokio::task::JoinHandle,
+};
+
+/// How long to wait for the browser to redirect back before giving up.
+pub const TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// How often the callback server checks whether its login was cancelled.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How many more times to try listening while a stopped server still holds the port.
+const BIND_ATTEMPTS: usize = 10;
+
+/// The login that is waiting for the browser, and the request that started it.
+struct Pending {
+    request: Id,
+    cancelled: Arc<AtomicBool>,
+    task: JoinHandle<()>,
+}
+
+impl Pending {
+    /// Stop waiting, and wait for the callback server to close so that its port is free again.
+    async fn stop(self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+        let _ = self.task.await;
+    }
+}
+
+static PENDING: Lazy<tokio::sync::Mutex<Option<Pending>>> = Lazy::new(Default::default);
+
+/// Start a login for `request`, replacing any login that is still waiting. The callback server
+/// is listening by the time this returns the page to open in the browser, so the redirect can't
+/// arrive before it. `on_done` gets the saved credentials once the browser redirects back, or why
+/// the login failed; it isn't called if the login is cancelled.
+pub async fn start(
+    login: Login,
+    request: Id,
+    on_done: impl FnOnce(Result<CodyCredentials>) + Send + 'static,
+) -> Result<String> {
+    let mut pending = PENDING.lock().await;
+    if let Some(previous) = pending.take() {
+        previous.stop().await;
+    }
+
+    let server = bind(login.port).await?;
+
+    let url = login.url();
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let runtime = tokio::runtime::Handle::current();
+    let network = crate::network::current();
+    let task = tokio::task::spawn_blocking({
+        let cancelled = cancelled.clone();
+        move || {
+            let result = login.listen(server, &cancelled, &runtime, &network);
+            if !cancelled.load(Ordering::Relaxed) {
+                on_done(result);
+            }
+        }
+    });
+
+    *pending = Some(Pending {
+        request,
+        cancelled,
+        task,
+    });
+
+    Ok(url)
+}
+
+/// Listen for the callback on `port`. A stopped server closes its port from its own thread, so
+/// a login that replaces one on the same port may have to wait a moment for it.
+async fn bind(port: usize) -> Result<tiny_http::Server> {
+    let mut attempts = 0;
+    loop {
+        match tiny_http::Server::http(format!("127.0.0.1:{port}")) {
+            Ok(server) => return Ok(server),
+            Err(_) if attempts < BIND_ATTEMPTS => {
+                attempts += 1;
+                tokio::time::sleep(POLL_INTERVAL / 5).await;
+            }
+            Err(err) => anyhow::bail!("Failed to listen on port {port}: {err}"),
+        }
+    }
+}
+
+/// Cancel the login started by `request`, if it is still waiting. Returns whether it was.
+pub async fn cancel(request: &Id) -> bool {
+    let mut pending = PENDING.lock().await;
+    match pending.take() {
+        Some(login) if login.request == *request => {
+            login.stop().await;
+            true
+        }
+        other => {
+            *pending = other;
+            false
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Login {
+    pub endpoint: String,
+    pub port: usize,
+    pub state: String,
+    pub profile: Option<String>,
+}
+
+impl Login {
+    pub fn new(endpoint: Option<String>, port: usize, profile: Option<String>) -> Self {
+        let endpoint = endpoint.unwrap_or_else(|| "https://sourcegraph.com".to_string());
+        let state = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect();
+
+        Self {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            port,
+            state,
+            profile,
+        }
+    }
+
+    /// The page to open in the browser.
+    pub fn url(&self) -> String {
+        format!(
+            "{}/user/settings/tokens/new/callback?requestFrom=NEOVIM-{}&state={}",
+            self.endpoint, self.port, self.state
+        )
+    }
+
+    /// Wait on `server` for the callback, then validate and save the credentials (over the
+    /// `network` of the editor that started the login). Returns the saved credentials. Gives up
+    /// early once `cancelled` is set.
+    fn listen(
+        &self,
+        server: tiny_http::Server,
+        cancelled: &AtomicBool,
+        runtime: &tokio::runtime::Handle,
+        network: &Network,
+    ) -> Result<CodyCredentials> {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            if cancelled.load(Ordering::Relaxed) {
+                anyhow::bail!("Login was cancelled");
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                anyhow::bail!("Timed out waiting for the browser login");
+            }
+
+            let Some(request) = server.recv_timeout(remaining.min(POLL_INTERVAL))? else {
+                continue;
+            };
+
+            // Create url to parse the parameters, a bit goofy but it is what it is
+            let url = Url::parse(&format!("http://127.0.0.1:{}{}", self.port, request.url()))?;
+            let query: HashMap<_, _> = url.query_pairs().into_owned().collect();
+
+            // Anything else hitting the port (favicons, stale tabs, ...) is turned away without
+            // ending the login.
+            let token = match (query.get("state"), query.get("token")) {
+                (Some(state), Some(token)) if *state == self.state => token.clone(),
+                _ => {
+                    let _ = request.respond(page(
+                        400,
+                        "Invalid login request",
+                        "This request does not belong to the login started from Neovim.",
+                    ));
+                    continue;
+                }
+            };
+
+            let credentials = CodyCredentials {
+                endpoint: Some(self.endpoint.clone()),
+                token: Some(token),
+                ..Default::default()
+            };
+
+            let saved = runtime
+                .block_on(network.scope(crate::validate_credentials(&credentials)))
+                .and_then(|_| auth::set_credentials(credentials.clone(), self.profile.clone()));
+
+            let response = match &saved {
+                Ok(_) => page(
+                    200,
+                    "Logged in",
+                    "Credentials have been saved to Neovim. You can close this tab.",
+                ),
+                Err(err) => page(500, "Login failed", &format!("{err:#}")),
+            };
+
+            let _ = request.respond(response);
+            return saved.map(|_| credentials);
+        }
+    }
+}
+
+fn page(status: u16, title: &str, message: &str) -> tiny_http::Response<std::io::Cursor<Vec<u8>>> {
+    let escape = |text: &str| {
+        text.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+    };
+
+    let body = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Sourcegraph: {title}</title></head>\
+         <body style=\"font-family: sans-serif; margin: 4em auto; max-width: 40em\">\
+         <h1>{title}</h1><p>{message}</p></body></html>",
+        title = escape(title),
+        message = escape(message),
+    );
+
+    let content_type = tiny_http::Header::from_bytes("Content-Type", "text/html; charset=utf-8")
+        .expect("to make header");
+
+    tiny_http::Response::from_string(body)
+        .with_status_code(status)
+        .with_header(content_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn a_new_login_replaces_the_pending_one() {
+        let port = 52917;
+        let connect = || std::net::TcpStream::connect(("127.0.0.1", port as u16));
+
+        let first = Login::new(None, port, None);
+        start(first, Id::Number(1), |_| {
+            panic!("a replaced login isn't finished")
+        })
+        .await
+        .unwrap();
+        assert!(connect().is_ok(), "listening before the url is returned");
+
+        let second = Login::new(None, port, None);
+        start(second, Id::Number(2), |_| {
+            panic!("a cancelled login isn't finished")
+        })
+        .await
+        .unwrap();
+        assert!(!cancel(&Id::Number(1)).await);
+        assert!(cancel(&Id::Number(2)).await);
+        assert!(!cancel(&Id::Number(2)).await);
+
+        let third = Login::new(None, port, None);
+        start(third, Id::Number(3), |_| {
+            panic!("a cancelled login isn't finished")
+        })
+        .await
+        .expect("the port is free again once cancelled");
+        assert!(cancel(&Id::Number(3)).await);
+    }
+}
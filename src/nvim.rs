@@ -1,83 +1,22 @@
 use {
-    crate::{
-        auth::{get_access_token, get_endpoint, CodyCredentials},
-        entry::{link, Entry},
-        get_cody_completions, get_embeddings_context,
-    },
+    crate::{auth, entry::Entry},
     anyhow::Result,
+    jsonrpc::{Id, Version},
+    router::{router, Activity, Context},
     serde::{Deserialize, Serialize},
-    serde_json::{json, Value},
-    sg_gql::dotcom_user::UserInfo,
-    sg_types::{Embedding, RecipeInfo, SearchResult},
-    std::{thread, time::Duration},
-    tokio::sync::mpsc::UnboundedSender,
+    serde_json::Value,
+    sg_gql::retry::RateLimit,
+    std::{
+        sync::atomic::{AtomicU64, Ordering},
+        time::Duration,
+    },
 };
 
-// TODO: I would like to explore this idea some more
-macro_rules! generate_request_and_response {
-    ( $( $name:ident { name: $rename:literal, request: $request:tt, response: $response:tt }, )* ) => {
-
-        #[derive(Serialize, Deserialize, Debug, Clone)]
-        pub struct MyRequest {
-            pub id: usize,
-
-            #[serde(flatten)]
-            pub data: RequestData,
-        }
-
-        #[derive(Serialize, Deserialize, Debug, Clone)]
-        #[serde(tag = "method", content = "params")]
-        pub enum MyRequestData {
-            $(
-                #[serde(rename = $rename)]
-                $name $request,
-            )*
-        }
-
-
-        // impl MyRequest {
-        //     pub async fn respond(self) -> Result<MyResponse> {
-        //         let Self { id, data } = self;
-        //         match data {
-        //             $(MyRequestData::$name(data) => {
-        //                 let result = ($blk)(id, data);
-        //                 Ok(MyResponse { id, result })
-        //             })*
-        //         }
-        //     }
-        // }
-
-        #[derive(Serialize, Deserialize, Debug, Clone)]
-        pub struct MyResponse {
-            pub id: usize,
-            pub result: MyResponseData,
-        }
-
-        #[derive(Serialize, Deserialize, Debug, Clone)]
-        #[serde(untagged)]
-        pub enum MyResponseData {
-            $($name $response,)*
-        }
-    };
-}
-
-generate_request_and_response!(
-    Echo {
-        name: "echo",
-        request: { message: String },
-        response: { message: String }
-    },
+pub mod methods;
+pub mod notifier;
+pub mod router;
 
-    Complete {
-        name: "complete",
-        request: {
-            message: String,
-            prefix: Option<String>,
-            temperature: Option<f64>,
-        },
-        response: { completion: String }
-    },
-);
+pub use notifier::{LogLevel, Notifier, Progress, ProgressValue};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ProtoEntry {
@@ -101,371 +40,217 @@ impl ProtoEntry {
 pub enum Message {
     Request(Request),
     Response(Response),
-    Notification(Notification),
+    Notification(jsonrpc::Notification<Notification>),
 }
 
 impl Message {
     pub fn notification(notification: Notification) -> Self {
-        Self::Notification(notification)
+        Self::Notification(jsonrpc::Notification::new(notification))
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// A request for one of the methods in [`methods`]. The params are decoded by the method, so that
+/// an unknown method or bad params can be answered with the matching error.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(from = "RawRequest")]
 pub struct Request {
-    pub id: usize,
+    pub id: Id,
 
-    #[serde(flatten)]
-    pub data: RequestData,
-}
+    /// The profile to send this request with, read from `params.profile`.
+    /// When not set, the current credentials are used.
+    #[serde(skip_serializing)]
+    pub profile: Option<String>,
 
-#[derive(Serialize, Deserialize, Clone)]
-pub struct SecretString(String);
+    pub method: String,
+    pub params: Value,
+}
 
-impl std::fmt::Debug for SecretString {
+/// Params are left out, since they can contain tokens. The router only logs the id and method
+/// name of each request.
+impl std::fmt::Debug for Request {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "<redacted>")
+        f.debug_struct("Request")
+            .field("id", &self.id)
+            .field("method", &self.method)
+            .field("profile", &self.profile)
+            .finish_non_exhaustive()
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(tag = "method", content = "params")]
-pub enum RequestData {
-    Echo {
-        message: String,
-        delay: Option<i32>,
-    },
-
-    Complete {
-        message: String,
-        prefix: Option<String>,
-        temperature: Option<f64>,
-    },
-
-    Embedding {
-        repo: String,
-        query: String,
-        code: i64,
-        text: i64,
-    },
-
-    #[serde(rename = "sourcegraph/get_entry")]
-    SourcegraphGetEntry {
-        path: String,
-    },
-
-    #[serde(rename = "sourcegraph/get_file_contents")]
-    SourcegraphFileContents {
-        remote: String,
-        oid: String,
-        path: String,
-    },
-
-    #[serde(rename = "sourcegraph/get_directory_contents")]
-    SourcegraphDirectoryContents {
-        remote: String,
-        oid: String,
-        path: String,
-    },
+#[derive(Deserialize)]
+struct RawRequest {
+    /// Only read to reject messages that aren't JSON-RPC 2.0.
+    #[serde(rename = "jsonrpc")]
+    _jsonrpc: Version,
+    id: Id,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
 
-    #[serde(rename = "sourcegraph/search")]
-    SourcegraphSearch {
-        query: String,
-    },
+impl From<RawRequest> for Request {
+    fn from(raw: RawRequest) -> Self {
+        let profile = raw
+            .params
+            .get("profile")
+            .and_then(Value::as_str)
+            .map(str::to_string);
 
-    #[serde(rename = "sourcegraph/info")]
-    SourcegraphInfo {
-        query: String,
-    },
-
-    #[serde(rename = "sourcegraph/link")]
-    SourcegraphLink {
-        path: String,
-        start_line: usize,
-        start_col: usize,
-        end_line: usize,
-        end_col: usize,
-    },
+        Self {
+            id: raw.id,
+            profile,
+            method: raw.method,
+            params: raw.params,
+        }
+    }
+}
 
-    #[serde(rename = "sourcegraph/get_remote_url")]
-    SourcegraphRemoteURL {
-        path: String,
-    },
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SecretString(String);
 
-    #[serde(rename = "sourcegraph/get_user_info")]
-    SourcegraphUserInfo {
-        testing: bool,
-    },
+impl std::fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<redacted>")
+    }
+}
 
-    #[serde(rename = "sourcegraph/auth")]
-    SourcegraphAuth {
-        endpoint: Option<String>,
-        token: Option<SecretString>,
-        clear: bool,
-    },
+/// Seconds without a response (or anything streamed, see [`Activity`]) before a request is
+/// abandoned, or 0 to never abandon requests. Requests run as their own tasks, so a request that
+/// hangs only holds up itself.
+static REQUEST_TIMEOUT: AtomicU64 = AtomicU64::new(60);
 
-    #[serde(rename = "sourcegraph/dotcom_login")]
-    SourcegraphDotcomLogin {
-        port: usize,
-    },
+pub fn set_request_timeout(timeout: Duration) {
+    REQUEST_TIMEOUT.store(timeout.as_secs(), Ordering::Relaxed);
 }
 
-#[derive(Debug)]
-pub enum NeovimTasks {
-    Authentication { port: usize },
+pub fn request_timeout() -> Option<Duration> {
+    match REQUEST_TIMEOUT.load(Ordering::Relaxed) {
+        0 => None,
+        seconds => Some(Duration::from_secs(seconds)),
+    }
 }
 
-#[allow(unused_variables)]
 impl Request {
-    pub async fn respond(self, tx: &UnboundedSender<NeovimTasks>) -> Result<Response> {
-        let Self { id, data } = self;
-        eprintln!("DATA : {:?}", data);
-
-        match data {
-            RequestData::Echo { message, delay } => {
-                if let Some(delay) = delay {
-                    thread::sleep(Duration::from_secs(delay as u64));
-                }
-
-                Ok(Response::new(id, ResponseData::Echo { message }))
-            }
-            RequestData::Complete {
-                message,
-                prefix,
-                temperature,
-            } => {
-                eprintln!("[sg-cody] complete: {id} - {prefix:?}");
-                let completion = match get_cody_completions(message, prefix, temperature).await {
-                    Ok(completion) => completion,
-                    Err(err) => {
-                        return Err(anyhow::anyhow!("failed to get completions: {err:?}"));
-                    }
-                };
-
-                Ok(Response::new(id, ResponseData::Complete { completion }))
-            }
-            RequestData::Embedding {
-                repo,
-                query,
-                code,
-                text,
-            } => {
-                eprintln!("[sg-cody] repo: {id} {repo}");
-                let embeddings = match get_embeddings_context(repo, query, code, text).await {
-                    Ok(embeddings) => embeddings,
-                    Err(err) => {
-                        return Err(anyhow::anyhow!("failed to get embeddings: {err:?}"));
+    pub async fn respond(self, notifier: &Notifier) -> Result<Response> {
+        let id = self.id.clone();
+        let profile = self.profile.clone();
+        let activity = Activity::default();
+        let response = auth::with_profile(profile, self.handle(notifier, activity.clone()));
+
+        let Some(timeout) = request_timeout() else {
+            return response.await;
+        };
+
+        tokio::pin!(response);
+        loop {
+            let deadline = activity.last() + timeout;
+            tokio::select! {
+                response = &mut response => return response,
+                _ = tokio::time::sleep_until(deadline.into()) => {
+                    if activity.last().elapsed() >= timeout {
+                        anyhow::bail!("Request {id} timed out after {}s", timeout.as_secs());
                     }
-                };
-
-                Ok(Response::new(id, ResponseData::Embedding { embeddings }))
-            }
-            RequestData::SourcegraphGetEntry { path } => {
-                let entry = Entry::new(&path).await?;
-                Ok(Response::new(
-                    id,
-                    ResponseData::SourcegraphGetEntry(ProtoEntry::from_entry(entry)),
-                ))
-            }
-            RequestData::SourcegraphFileContents { remote, oid, path } => {
-                let contents = crate::get_file_contents(&remote, &oid, &path)
-                    .await?
-                    .split('\n')
-                    .map(|s| s.to_string())
-                    .collect::<Vec<String>>();
-                Ok(Response::new(
-                    id,
-                    ResponseData::SourcegraphFileContents(contents),
-                ))
-            }
-            RequestData::SourcegraphDirectoryContents { remote, oid, path } => {
-                let contents = crate::get_remote_directory_contents(&remote, &oid, &path)
-                    .await?
-                    .into_iter()
-                    .flat_map(|e| Entry::from_info(e).map(ProtoEntry::from_entry))
-                    .collect::<Vec<_>>();
-
-                Ok(Response::new(
-                    id,
-                    ResponseData::SourcegraphDirectoryContents(contents),
-                ))
-            }
-            RequestData::SourcegraphSearch { query } => {
-                let result = crate::get_search(query).await?;
-                Ok(Response::new(id, ResponseData::SourcegraphSearch(result)))
-            }
-            RequestData::SourcegraphInfo { .. } => {
-                eprintln!("Got Sg info request");
-                let version = crate::get_sourcegraph_version().await?;
-                let nvim_version = env!("CARGO_PKG_VERSION");
-
-                let value = json!({
-                    "sourcegraph_version": version,
-                    "sg_nvim_version": nvim_version,
-                    "endpoint": get_endpoint(),
-                    "access_token_set": get_access_token().is_some()
-                });
-
-                Ok(Response::new(id, ResponseData::SourcegraphInfo(value)))
+                }
             }
-            RequestData::SourcegraphLink {
-                path,
-                start_line,
-                start_col,
-                end_line,
-                end_col,
-            } => {
-                let link = match Entry::new(&path).await? {
-                    Entry::File(file) => {
-                        let endpoint = get_endpoint();
-                        let remote = file.remote.0;
-                        let path = file.path;
-
-                        let prefix = format!("{endpoint}/{remote}/-/blob/{path}");
-                        if start_line == end_line && start_col == end_col {
-                            if start_col == 0 {
-                                format!("{prefix}?L{start_line}")
-                            } else {
-                                format!("{prefix}?L{start_line}:{start_col}")
-                            }
-                        } else {
-                            format!("{prefix}?L{start_line}:{start_col}-{end_line}:{end_col}")
-                        }
-                    }
-                    Entry::Directory(dir) => {
-                        let endpoint = get_endpoint();
-                        let remote = dir.remote.0;
-                        let path = dir.path;
-
-                        format!("{endpoint}/{remote}/-/tree/{path}")
-                    }
-                    Entry::Repo(repo) => {
-                        let endpoint = get_endpoint();
-                        let remote = repo.remote.0;
-                        let oid = repo.oid.0;
+        }
+    }
 
-                        format!("{endpoint}/{remote}@{oid}")
-                    }
-                };
+    async fn handle(self, notifier: &Notifier, activity: Activity) -> Result<Response> {
+        let Self {
+            id, method, params, ..
+        } = self;
 
-                Ok(Response::new(id, ResponseData::SourcegraphLink(link)))
-            }
-            RequestData::SourcegraphRemoteURL { path } => {
-                let url = match link::repo_from_path(&path) {
-                    Ok(repo) => link::get_repo_name(&repo).ok(),
-                    _ => Some(path),
-                };
-                Ok(Response::new(id, ResponseData::SourcegraphRemoteURL(url)))
-            }
-            RequestData::SourcegraphUserInfo { .. } => {
-                eprintln!("Got Sg user info request");
-                let user_info = crate::get_user_info().await?;
-
-                Ok(Response::new(
-                    id,
-                    ResponseData::SourcegraphUserInfo(user_info),
-                ))
-            }
-            RequestData::SourcegraphAuth {
-                endpoint,
-                token,
-                clear,
-            } => {
-                use crate::auth;
-
-                if clear {
-                    auth::set_credentials(CodyCredentials::default())?;
-                } else {
-                    let credentials = CodyCredentials {
-                        endpoint,
-                        token: token.map(|t| t.0),
-                    };
-
-                    if credentials.token.is_some() || credentials.endpoint.is_some() {
-                        auth::set_credentials(credentials)?;
-                    }
-                }
+        let cx = Context {
+            id: id.clone(),
+            notifier: notifier.clone(),
+            activity,
+        };
 
-                Ok(Response::new(
-                    id,
-                    ResponseData::SourcegraphAuth {
-                        endpoint: Some(auth::get_endpoint()),
-                        token: auth::get_access_token().map(|t| SecretString(t)),
-                    },
-                ))
-            }
-            RequestData::SourcegraphDotcomLogin { port } => {
-                // Start http server
-                tx.send(NeovimTasks::Authentication { port })?;
-
-                // Send response that we're ready.
-                Ok(Response::new(
-                    id,
-                    ResponseData::Echo {
-                        message: "... Starting Sourcegraph Login in Browser ... ".to_string(),
-                    },
-                ))
-            }
-        }
+        let result = router().call(&method, params, cx).await?;
+        Ok(Response::new(id, result))
     }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Response {
-    pub id: usize,
-    pub result: ResponseData,
+    pub jsonrpc: Version,
+    pub id: Id,
+    pub result: Value,
 }
 
 impl Response {
-    pub fn new(id: usize, result: ResponseData) -> Self {
-        Self { id, result }
+    pub fn new(id: Id, result: Value) -> Self {
+        Self {
+            jsonrpc: Version,
+            id,
+            result,
+        }
     }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(untagged)]
-pub enum ResponseData {
-    Echo {
-        message: String,
+#[serde(tag = "method", content = "params")]
+pub enum Notification {
+    /// Sent by the editor to abort the request with `id`.
+    #[serde(rename = "$/cancel")]
+    Cancel {
+        id: Id,
     },
-    Complete {
-        completion: String,
+
+    /// Sent when an editor connects. The token is only sent when asked for with
+    /// `sourcegraph/auth`, not to whatever connects.
+    #[serde(rename = "initialize")]
+    Initialize {
+        endpoint: Option<String>,
     },
-    Embedding {
-        embeddings: Vec<Embedding>,
+
+    /// Progress of a long running operation, see [`Progress`].
+    #[serde(rename = "$/progress")]
+    Progress {
+        token: String,
+        value: ProgressValue,
     },
-    ListRecipes {
-        recipes: Vec<RecipeInfo>,
+
+    /// A message for the editor's log.
+    #[serde(rename = "log")]
+    Log {
+        level: LogLevel,
+        message: String,
     },
-    SourcegraphGetEntry(ProtoEntry),
-    SourcegraphFileContents(Vec<String>),
-    SourcegraphDirectoryContents(Vec<ProtoEntry>),
-    SourcegraphSearch(Vec<SearchResult>),
-    SourcegraphInfo(Value),
-    SourcegraphLink(String),
-    SourcegraphRemoteURL(Option<String>),
-    SourcegraphUserInfo(UserInfo),
-
-    SourcegraphAuth {
-        endpoint: Option<String>,
-        token: Option<SecretString>,
+
+    #[serde(rename = "display_text")]
+    DisplayText {
+        message: String,
     },
-}
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(tag = "method", content = "params")]
-pub enum Notification {
-    #[serde(rename = "initialize")]
-    Initialize {
+    /// The current credentials changed, sent with the same fields as `initialize`.
+    #[serde(rename = "credentials_changed")]
+    CredentialsChanged {
         endpoint: Option<String>,
         token: Option<String>,
     },
 
-    #[serde(rename = "display_text")]
-    DisplayText {
+    /// A browser login for `endpoint` finished, failing with `error` if it is set.
+    #[serde(rename = "login")]
+    Login {
+        endpoint: String,
+        error: Option<String>,
+    },
+
+    /// An endpoint started or stopped rate limiting requests.
+    #[serde(rename = "rate_limit")]
+    RateLimit(RateLimit),
+
+    /// The access token for `endpoint` was rejected, so the user must log in again.
+    #[serde(rename = "auth_invalid")]
+    AuthInvalid {
+        endpoint: String,
         message: String,
     },
 
+    /// New text of the streamed completion for request `id`, see `Complete`.
     UpdateChat {
+        id: Id,
         message: String,
     },
     Hack {
@@ -0,0 +1,933 @@
+//! The methods sg-nvim-agent answers, see [`super::router`].
+
+use {
+    super::{
+        router::{params, router as get_router, Context, Method, Router},
+        set_request_timeout, LogLevel, Notification, ProtoEntry, SecretString,
+    },
+    crate::{
+        auth::{self, get_access_token, get_endpoint, CodyCredentials, ProfileInfo},
+        autocomplete::{self, AutocompleteRequest, Candidate},
+        chat::{self, Transcript, TranscriptSummary},
+        context::{self, Citation, ContextOptions, Snippet},
+        entry::{link, Entry},
+        get_cody_completions, get_cody_models, get_embeddings_context,
+        login::{self, Login},
+        network::{self, NetworkConfig},
+        permalink::{self, LinkOptions, LinkRange},
+        stream_cody_completions,
+    },
+    anyhow::Result,
+    futures::future::BoxFuture,
+    serde::{Deserialize, Serialize},
+    serde_json::{json, Value},
+    sg_gql::{completions_stream::CompletionUsage, dotcom_user::UserInfo},
+    sg_types::{CodyModel, CompletionParameters, Embedding, SearchResult},
+    std::time::Duration,
+};
+
+pub fn router() -> Router {
+    Router::default()
+        .register::<Initialize>()
+        .register::<GetSchema>()
+        .register::<Echo>()
+        .register::<Complete>()
+        .register::<Embeddings>()
+        .register::<CodyContext>()
+        .register::<CodyModels>()
+        .register::<Autocomplete>()
+        .register::<GetEntry>()
+        .register::<FileContents>()
+        .register::<DirectoryContents>()
+        .register::<Search>()
+        .register::<Info>()
+        .register::<Link>()
+        .register::<RemoteUrl>()
+        .register::<UserInformation>()
+        .register::<Auth>()
+        .register::<ListProfiles>()
+        .register::<SwitchProfile>()
+        .register::<RemoveProfile>()
+        .register::<RouteHost>()
+        .register::<BrowserLogin>()
+        .register::<ChatNew>()
+        .register::<ChatSubmit>()
+        .register::<ChatGet>()
+        .register::<ChatList>()
+        .register::<ChatDelete>()
+}
+
+params! {
+    pub struct NoParams {}
+}
+
+params! {
+    pub struct InitializeParams {
+        network: NetworkConfig = default,
+        /// Seconds before a request is abandoned (0 for never), see [`set_request_timeout`].
+        request_timeout: Option<u64> = default,
+    }
+}
+
+/// Settings from the editor, sent before any other request.
+pub struct Initialize;
+
+impl Method for Initialize {
+    const NAME: &'static str = "initialize";
+    type Params = InitializeParams;
+    type Result = Value;
+
+    fn handle(params: Self::Params, _: Context) -> BoxFuture<'static, Result<Self::Result>> {
+        Box::pin(async move {
+            network::configure(params.network)?;
+            if let Some(timeout) = params.request_timeout {
+                set_request_timeout(Duration::from_secs(timeout));
+            }
+
+            Ok(json!({ "network": network::info() }))
+        })
+    }
+}
+
+/// The params of every method.
+pub struct GetSchema;
+
+impl Method for GetSchema {
+    const NAME: &'static str = "$/schema";
+    type Params = NoParams;
+    type Result = Value;
+
+    fn handle(_: Self::Params, _: Context) -> BoxFuture<'static, Result<Self::Result>> {
+        Box::pin(async move { Ok(get_router().schema()) })
+    }
+}
+
+params! {
+    pub struct EchoParams {
+        message: String,
+        delay: Option<i32>,
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EchoResult {
+    pub message: String,
+}
+
+/// Used by tests, to check that messages still make it through.
+pub struct Echo;
+
+impl Method for Echo {
+    const NAME: &'static str = "Echo";
+    type Params = EchoParams;
+    type Result = EchoResult;
+
+    fn handle(params: Self::Params, _: Context) -> BoxFuture<'static, Result<Self::Result>> {
+        Box::pin(async move {
+            if let Some(delay) = params.delay {
+                tokio::time::sleep(Duration::from_secs(delay as u64)).await;
+            }
+
+            Ok(EchoResult {
+                message: params.message,
+            })
+        })
+    }
+}
+
+params! {
+    pub struct CompleteParams {
+        message: String,
+        prefix: Option<String>,
+        temperature: Option<f64>,
+        /// One of the models from `cody/models`.
+        model: Option<String> = default,
+        max_tokens: Option<i64> = default,
+        stop_sequences: Vec<String> = default,
+        top_k: Option<i64> = default,
+        /// Between 0 and 1, like `0.9`.
+        top_p: Option<f64> = default,
+        /// Send the completion as it is generated, with `UpdateChat` notifications.
+        stream: bool = default,
+        /// Where the question was asked, to send relevant code with it.
+        context: Option<ContextOptions> = default,
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CompleteResult {
+    pub completion: String,
+
+    /// The snippets sent as context.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub citations: Vec<Citation>,
+
+    /// Only known for streamed completions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<CompletionUsage>,
+}
+
+pub struct Complete;
+
+impl Method for Complete {
+    const NAME: &'static str = "Complete";
+    type Params = CompleteParams;
+    type Result = CompleteResult;
+
+    fn handle(params: Self::Params, cx: Context) -> BoxFuture<'static, Result<Self::Result>> {
+        Box::pin(async move {
+            let CompleteParams {
+                message,
+                prefix,
+                temperature,
+                model,
+                max_tokens,
+                stop_sequences,
+                top_k,
+                top_p,
+                stream,
+                context,
+            } = params;
+
+            let parameters = CompletionParameters {
+                model,
+                temperature,
+                max_tokens,
+                stop_sequences,
+                top_k,
+                top_p,
+            };
+
+            cx.log(LogLevel::Debug, format!("complete: {} - {prefix:?}", cx.id));
+            let snippets = assemble_context(&message, context, &cx).await;
+            let citations = snippets.iter().map(Snippet::citation).collect();
+
+            // Cancelling the request drops the stream, which closes the connection
+            if stream {
+                let on_delta = |delta: &str| {
+                    cx.notify(Notification::UpdateChat {
+                        id: cx.id.clone(),
+                        message: delta.to_string(),
+                    })
+                };
+
+                let (completion, usage) =
+                    stream_cody_completions(message, prefix, &snippets, parameters, on_delta)
+                        .await?;
+
+                return Ok(CompleteResult {
+                    completion,
+                    citations,
+                    usage: Some(usage),
+                });
+            }
+
+            let completion =
+                match get_cody_completions(message, prefix, &snippets, parameters).await {
+                    Ok(completion) => completion,
+                    Err(err) => {
+                        return Err(anyhow::anyhow!("failed to get completions: {err:?}"));
+                    }
+                };
+
+            Ok(CompleteResult {
+                completion,
+                citations,
+                usage: None,
+            })
+        })
+    }
+}
+
+/// Assemble the context for `query`, if the editor sent any options for it.
+async fn assemble_context(
+    query: &str,
+    options: Option<ContextOptions>,
+    cx: &Context,
+) -> Vec<Snippet> {
+    let Some(options) = options else {
+        return vec![];
+    };
+
+    let progress = cx.progress("Gathering context");
+    let snippets = context::assemble(query, &options, cx).await;
+    progress.finish(format!("{} snippets", snippets.len()));
+
+    snippets
+}
+
+params! {
+    pub struct EmbeddingParams {
+        repo: String,
+        query: String,
+        code: i64,
+        text: i64,
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EmbeddingResult {
+    pub embeddings: Vec<Embedding>,
+}
+
+pub struct Embeddings;
+
+impl Method for Embeddings {
+    const NAME: &'static str = "Embedding";
+    type Params = EmbeddingParams;
+    type Result = EmbeddingResult;
+
+    fn handle(params: Self::Params, cx: Context) -> BoxFuture<'static, Result<Self::Result>> {
+        Box::pin(async move {
+            let EmbeddingParams {
+                repo,
+                query,
+                code,
+                text,
+            } = params;
+
+            cx.log(LogLevel::Debug, format!("embeddings: {} {repo}", cx.id));
+            let embeddings = match get_embeddings_context(repo, query, code, text).await {
+                Ok(embeddings) => embeddings,
+                Err(err) => {
+                    return Err(anyhow::anyhow!("failed to get embeddings: {err:?}"));
+                }
+            };
+
+            Ok(EmbeddingResult { embeddings })
+        })
+    }
+}
+
+/// The models the instance allows, for the `model` of `Complete` and `cody/chat/submit`.
+pub struct CodyModels;
+
+impl Method for CodyModels {
+    const NAME: &'static str = "cody/models";
+    type Params = NoParams;
+    type Result = Vec<CodyModel>;
+
+    fn handle(_: Self::Params, _: Context) -> BoxFuture<'static, Result<Self::Result>> {
+        Box::pin(get_cody_models())
+    }
+}
+
+params! {
+    pub struct AutocompleteParams {
+        /// The document before the cursor.
+        prefix: String,
+        /// The document after the cursor.
+        suffix: String,
+        path: Option<String> = default,
+        language: Option<String> = default,
+        /// How many candidates to request, up to 3. Defaults to 1.
+        candidates: Option<usize> = default,
+        /// One of the models from `cody/models`, the instance's completion model by default.
+        model: Option<String> = default,
+    }
+}
+
+/// Inline completions at the cursor, see [`autocomplete`].
+pub struct Autocomplete;
+
+impl Method for Autocomplete {
+    const NAME: &'static str = "cody/autocomplete";
+    type Params = AutocompleteParams;
+    type Result = Vec<Candidate>;
+
+    fn handle(params: Self::Params, _: Context) -> BoxFuture<'static, Result<Self::Result>> {
+        let AutocompleteParams {
+            prefix,
+            suffix,
+            path,
+            language,
+            candidates,
+            model,
+        } = params;
+
+        Box::pin(autocomplete::complete(AutocompleteRequest {
+            prefix,
+            suffix,
+            path,
+            language,
+            candidates,
+            model,
+        }))
+    }
+}
+
+params! {
+    pub struct CodyContextParams {
+        query: String,
+        context: ContextOptions = default,
+    }
+}
+
+/// The context that would be sent with `query`, so the editor can show it before asking.
+pub struct CodyContext;
+
+impl Method for CodyContext {
+    const NAME: &'static str = "cody/context";
+    type Params = CodyContextParams;
+    type Result = Vec<Snippet>;
+
+    fn handle(params: Self::Params, cx: Context) -> BoxFuture<'static, Result<Self::Result>> {
+        Box::pin(
+            async move { Ok(assemble_context(&params.query, Some(params.context), &cx).await) },
+        )
+    }
+}
+
+params! {
+    pub struct PathParams {
+        path: String,
+    }
+}
+
+pub struct GetEntry;
+
+impl Method for GetEntry {
+    const NAME: &'static str = "sourcegraph/get_entry";
+    type Params = PathParams;
+    type Result = ProtoEntry;
+
+    fn handle(params: Self::Params, _: Context) -> BoxFuture<'static, Result<Self::Result>> {
+        Box::pin(async move {
+            let entry = Entry::new(&params.path).await?;
+            Ok(ProtoEntry::from_entry(entry))
+        })
+    }
+}
+
+params! {
+    pub struct ContentsParams {
+        remote: String,
+        oid: String,
+        path: String,
+    }
+}
+
+pub struct FileContents;
+
+impl Method for FileContents {
+    const NAME: &'static str = "sourcegraph/get_file_contents";
+    type Params = ContentsParams;
+    type Result = Vec<String>;
+
+    fn handle(params: Self::Params, cx: Context) -> BoxFuture<'static, Result<Self::Result>> {
+        Box::pin(async move {
+            let ContentsParams { remote, oid, path } = params;
+            let progress = cx.progress(format!("Fetching {remote}/{path}"));
+            let contents = crate::get_file_contents(&remote, &oid, &path)
+                .await?
+                .split('\n')
+                .map(|s| s.to_string())
+                .collect::<Vec<String>>();
+
+            progress.finish(format!("{} lines", contents.len()));
+            Ok(contents)
+        })
+    }
+}
+
+pub struct DirectoryContents;
+
+impl Method for DirectoryContents {
+    const NAME: &'static str = "sourcegraph/get_directory_contents";
+    type Params = ContentsParams;
+    type Result = Vec<ProtoEntry>;
+
+    fn handle(params: Self::Params, cx: Context) -> BoxFuture<'static, Result<Self::Result>> {
+        Box::pin(async move {
+            let ContentsParams { remote, oid, path } = params;
+            let progress = cx.progress(format!("Listing {remote}/{path}"));
+            let contents = crate::get_remote_directory_contents(&remote, &oid, &path)
+                .await?
+                .into_iter()
+                .flat_map(|e| Entry::from_info(e).map(ProtoEntry::from_entry))
+                .collect::<Vec<_>>();
+
+            progress.finish(format!("{} entries", contents.len()));
+            Ok(contents)
+        })
+    }
+}
+
+params! {
+    pub struct QueryParams {
+        query: String,
+    }
+}
+
+pub struct Search;
+
+impl Method for Search {
+    const NAME: &'static str = "sourcegraph/search";
+    type Params = QueryParams;
+    type Result = Vec<SearchResult>;
+
+    fn handle(params: Self::Params, cx: Context) -> BoxFuture<'static, Result<Self::Result>> {
+        Box::pin(async move {
+            let progress = cx.progress(format!("Searching {}", params.query));
+            let results = crate::get_search(params.query).await?;
+
+            progress.finish(format!("{} results", results.len()));
+            Ok(results)
+        })
+    }
+}
+
+pub struct Info;
+
+impl Method for Info {
+    const NAME: &'static str = "sourcegraph/info";
+    type Params = QueryParams;
+    type Result = Value;
+
+    fn handle(_: Self::Params, _: Context) -> BoxFuture<'static, Result<Self::Result>> {
+        Box::pin(async move {
+            // Reported even when the instance can't be reached, since that is when they matter
+            let nvim_version = env!("CARGO_PKG_VERSION");
+            let (credential_store, credential_error) = auth::get_backend();
+            let endpoint = get_endpoint().ok();
+            let access_token_set = get_access_token().await.is_some();
+
+            let (version, version_error) = match crate::get_sourcegraph_version().await {
+                Ok(version) => (Some(version), None),
+                Err(err) => (None, Some(format!("{err:#}"))),
+            };
+
+            Ok(json!({
+                "sourcegraph_version": version,
+                "sourcegraph_version_error": version_error,
+                "sg_nvim_version": nvim_version,
+                "endpoint": endpoint,
+                "access_token_set": access_token_set,
+                "credential_store": credential_store,
+                "credential_error": credential_error,
+                "network": network::info(),
+                "rate_limits": sg_gql::retry::rate_limits(),
+            }))
+        })
+    }
+}
+
+params! {
+    pub struct LinkParams {
+        path: String,
+        start_line: usize,
+        start_col: usize,
+        end_line: usize,
+        end_col: usize,
+        /// Additional ranges to highlight, used instead of the single range when non-empty.
+        ranges: Vec<LinkRange> = default,
+        permalink: bool = default,
+        code_host: bool = default,
+    }
+}
+
+pub struct Link;
+
+impl Method for Link {
+    const NAME: &'static str = "sourcegraph/link";
+    type Params = LinkParams;
+    type Result = String;
+
+    fn handle(params: Self::Params, _: Context) -> BoxFuture<'static, Result<Self::Result>> {
+        Box::pin(async move {
+            let LinkParams {
+                path,
+                start_line,
+                start_col,
+                end_line,
+                end_col,
+                ranges,
+                permalink,
+                code_host,
+            } = params;
+
+            let ranges = if ranges.is_empty() {
+                vec![LinkRange {
+                    start_line,
+                    start_col,
+                    end_line,
+                    end_col,
+                }]
+            } else {
+                ranges
+            };
+
+            let entry = Entry::new(&path).await?;
+            let options = LinkOptions {
+                permalink,
+                code_host,
+            };
+
+            permalink::make_link(&entry, &ranges, options).await
+        })
+    }
+}
+
+pub struct RemoteUrl;
+
+impl Method for RemoteUrl {
+    const NAME: &'static str = "sourcegraph/get_remote_url";
+    type Params = PathParams;
+    type Result = Option<String>;
+
+    fn handle(params: Self::Params, _: Context) -> BoxFuture<'static, Result<Self::Result>> {
+        Box::pin(async move {
+            let path = params.path;
+            Ok(match link::repo_from_path(&path) {
+                Ok(repo) => link::get_repo_name(&repo).ok(),
+                _ => Some(path),
+            })
+        })
+    }
+}
+
+params! {
+    pub struct UserInfoParams {
+        testing: bool,
+    }
+}
+
+pub struct UserInformation;
+
+impl Method for UserInformation {
+    const NAME: &'static str = "sourcegraph/get_user_info";
+    type Params = UserInfoParams;
+    type Result = UserInfo;
+
+    fn handle(_: Self::Params, _: Context) -> BoxFuture<'static, Result<Self::Result>> {
+        Box::pin(async move { crate::get_user_info().await })
+    }
+}
+
+params! {
+    pub struct AuthParams {
+        endpoint: Option<String>,
+        token: Option<SecretString>,
+        clear: bool,
+        /// Command that prints a short-lived token, run again whenever the token expires.
+        token_command: Option<String> = default,
+        /// Save the credentials to this profile (and make it active) instead of the active one.
+        profile: Option<String> = default,
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuthResult {
+    pub endpoint: Option<String>,
+    pub token: Option<SecretString>,
+}
+
+pub struct Auth;
+
+impl Method for Auth {
+    const NAME: &'static str = "sourcegraph/auth";
+    type Params = AuthParams;
+    type Result = AuthResult;
+
+    fn handle(params: Self::Params, _: Context) -> BoxFuture<'static, Result<Self::Result>> {
+        Box::pin(async move {
+            let AuthParams {
+                endpoint,
+                token,
+                clear,
+                token_command,
+                profile,
+            } = params;
+
+            if clear {
+                auth::set_credentials(CodyCredentials::default(), profile)?;
+            } else {
+                let credentials = CodyCredentials {
+                    endpoint,
+                    token: token.map(|t| t.0),
+                    token_command,
+                };
+
+                let has_token = credentials.token.is_some() || credentials.token_command.is_some();
+
+                if has_token {
+                    crate::validate_credentials(&credentials).await?;
+                }
+
+                if has_token || credentials.endpoint.is_some() {
+                    auth::set_credentials(credentials, profile)?;
+                }
+            }
+
+            Ok(AuthResult {
+                endpoint: Some(auth::get_endpoint()?),
+                token: auth::get_access_token().await.map(SecretString),
+            })
+        })
+    }
+}
+
+pub struct ListProfiles;
+
+impl Method for ListProfiles {
+    const NAME: &'static str = "sourcegraph/profiles/list";
+    type Params = NoParams;
+    type Result = Vec<ProfileInfo>;
+
+    fn handle(_: Self::Params, _: Context) -> BoxFuture<'static, Result<Self::Result>> {
+        Box::pin(async move { Ok(auth::list_profiles()) })
+    }
+}
+
+params! {
+    pub struct ProfileParams {
+        name: String,
+    }
+}
+
+pub struct SwitchProfile;
+
+impl Method for SwitchProfile {
+    const NAME: &'static str = "sourcegraph/profiles/switch";
+    type Params = ProfileParams;
+    type Result = Vec<ProfileInfo>;
+
+    fn handle(params: Self::Params, _: Context) -> BoxFuture<'static, Result<Self::Result>> {
+        Box::pin(async move {
+            auth::switch_profile(&params.name)?;
+            Ok(auth::list_profiles())
+        })
+    }
+}
+
+pub struct RemoveProfile;
+
+impl Method for RemoveProfile {
+    const NAME: &'static str = "sourcegraph/profiles/remove";
+    type Params = ProfileParams;
+    type Result = Vec<ProfileInfo>;
+
+    fn handle(params: Self::Params, _: Context) -> BoxFuture<'static, Result<Self::Result>> {
+        Box::pin(async move {
+            auth::remove_profile(&params.name)?;
+            Ok(auth::list_profiles())
+        })
+    }
+}
+
+params! {
+    pub struct RouteParams {
+        host: String,
+        /// Name of the profile (`profile` is already taken by the request itself).
+        route: Option<String> = default,
+    }
+}
+
+/// Send requests for repositories on `host` to `route`, or remove the route without one.
+pub struct RouteHost;
+
+impl Method for RouteHost {
+    const NAME: &'static str = "sourcegraph/profiles/route";
+    type Params = RouteParams;
+    type Result = Vec<ProfileInfo>;
+
+    fn handle(params: Self::Params, _: Context) -> BoxFuture<'static, Result<Self::Result>> {
+        Box::pin(async move {
+            auth::set_route(&params.host, params.route)?;
+            Ok(auth::list_profiles())
+        })
+    }
+}
+
+params! {
+    pub struct LoginParams {
+        endpoint: Option<String> = default,
+        port: usize,
+        profile: Option<String> = default,
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LoginResult {
+    pub url: String,
+}
+
+/// Start a browser login against `endpoint`, listening for the callback on `port`.
+pub struct BrowserLogin;
+
+impl Method for BrowserLogin {
+    const NAME: &'static str = "sourcegraph/login";
+    const ALIASES: &'static [&'static str] = &["sourcegraph/dotcom_login"];
+    type Params = LoginParams;
+    type Result = LoginResult;
+
+    fn handle(params: Self::Params, cx: Context) -> BoxFuture<'static, Result<Self::Result>> {
+        Box::pin(async move {
+            // Start http server, then let the editor open the browser
+            let login = Login::new(params.endpoint, params.port, params.profile);
+            let endpoint = login.endpoint.clone();
+
+            let progress = cx.progress(format!("Waiting for login to {endpoint}"));
+            let id = cx.id.clone();
+            let url = login::start(login, id, move |result| {
+                let error = result.err().map(|err| format!("{err:#}"));
+                match &error {
+                    Some(_) => progress.finish("Login failed"),
+                    None => progress.finish("Logged in"),
+                }
+
+                cx.notify(Notification::Login { endpoint, error });
+            })
+            .await?;
+
+            Ok(LoginResult { url })
+        })
+    }
+}
+
+params! {
+    pub struct ChatNewParams {
+        title: Option<String> = default,
+        /// The language the conversation is about, so Cody can answer for it.
+        language: Option<String> = default,
+    }
+}
+
+pub struct ChatNew;
+
+impl Method for ChatNew {
+    const NAME: &'static str = "cody/chat/new";
+    type Params = ChatNewParams;
+    type Result = Transcript;
+
+    fn handle(params: Self::Params, _: Context) -> BoxFuture<'static, Result<Self::Result>> {
+        Box::pin(async move { chat::new_chat(params.title, params.language).await })
+    }
+}
+
+params! {
+    pub struct ChatSubmitParams {
+        id: String,
+        message: String,
+        temperature: Option<f64> = default,
+        /// One of the models from `cody/models`.
+        model: Option<String> = default,
+        /// Send the reply as it is generated, with `UpdateChat` notifications.
+        stream: bool = default,
+        /// Where the message was written, to send relevant code with it.
+        context: Option<ContextOptions> = default,
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatSubmitResult {
+    pub reply: String,
+
+    /// The snippets sent as context.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub citations: Vec<Citation>,
+
+    /// Only known for streamed replies.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<CompletionUsage>,
+}
+
+/// Send a message in a conversation. The exchange is saved once Cody has replied.
+pub struct ChatSubmit;
+
+impl Method for ChatSubmit {
+    const NAME: &'static str = "cody/chat/submit";
+    type Params = ChatSubmitParams;
+    type Result = ChatSubmitResult;
+
+    fn handle(params: Self::Params, cx: Context) -> BoxFuture<'static, Result<Self::Result>> {
+        Box::pin(async move {
+            let ChatSubmitParams {
+                id,
+                message,
+                temperature,
+                model,
+                stream,
+                context,
+            } = params;
+
+            let parameters = CompletionParameters {
+                model,
+                temperature,
+                ..Default::default()
+            };
+
+            let snippets = assemble_context(&message, context, &cx).await;
+            let on_delta = stream.then_some(|delta: &str| {
+                cx.notify(Notification::UpdateChat {
+                    id: cx.id.clone(),
+                    message: delta.to_string(),
+                })
+            });
+
+            let (reply, usage) =
+                chat::submit(&id, message, &snippets, parameters, on_delta).await?;
+
+            Ok(ChatSubmitResult {
+                reply,
+                citations: snippets.iter().map(Snippet::citation).collect(),
+                usage,
+            })
+        })
+    }
+}
+
+params! {
+    pub struct ChatIdParams {
+        id: String,
+    }
+}
+
+pub struct ChatGet;
+
+impl Method for ChatGet {
+    const NAME: &'static str = "cody/chat/get";
+    type Params = ChatIdParams;
+    type Result = Transcript;
+
+    fn handle(params: Self::Params, _: Context) -> BoxFuture<'static, Result<Self::Result>> {
+        Box::pin(async move { chat::get_chat(&params.id).await })
+    }
+}
+
+pub struct ChatList;
+
+impl Method for ChatList {
+    const NAME: &'static str = "cody/chat/list";
+    type Params = NoParams;
+    type Result = Vec<TranscriptSummary>;
+
+    fn handle(_: Self::Params, cx: Context) -> BoxFuture<'static, Result<Self::Result>> {
+        Box::pin(async move { list_chats(&cx).await })
+    }
+}
+
+pub struct ChatDelete;
+
+impl Method for ChatDelete {
+    const NAME: &'static str = "cody/chat/delete";
+    type Params = ChatIdParams;
+    type Result = Vec<TranscriptSummary>;
+
+    fn handle(params: Self::Params, cx: Context) -> BoxFuture<'static, Result<Self::Result>> {
+        Box::pin(async move {
+            chat::delete_chat(&params.id).await?;
+            list_chats(&cx).await
+        })
+    }
+}
+
+/// Every conversation, warning the editor about any saved conversation that couldn't be read.
+async fn list_chats(cx: &Context) -> Result<Vec<TranscriptSummary>> {
+    let (chats, skipped) = chat::list_chats().await?;
+    for problem in skipped {
+        cx.log(LogLevel::Warn, problem);
+    }
+
+    Ok(chats)
+}
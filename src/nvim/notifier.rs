@@ -0,0 +1,174 @@
+//! Notifications from request handlers to the editor.
+//!
+//! Each connection has one [`Notifier`], and a task that writes whatever is sent to it. Handlers
+//! reach it through their [`Context`](super::router::Context) to log messages or report the
+//! progress of long operations.
+
+use {
+    super::Notification,
+    serde::{Deserialize, Serialize},
+    std::sync::atomic::{AtomicU64, Ordering},
+    tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+};
+
+#[derive(Debug, Clone)]
+pub struct Notifier(UnboundedSender<Notification>);
+
+impl Notifier {
+    pub fn channel() -> (Self, UnboundedReceiver<Notification>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (Self(tx), rx)
+    }
+
+    /// Send `notification`. Dropped if the editor has disconnected.
+    pub fn send(&self, notification: Notification) {
+        let _ = self.0.send(notification);
+    }
+
+    pub fn log(&self, level: LogLevel, message: impl Into<String>) {
+        self.send(Notification::Log {
+            level,
+            message: message.into(),
+        })
+    }
+
+    /// Start reporting progress for `title`, until the returned [`Progress`] is finished or
+    /// dropped.
+    pub fn progress(&self, title: impl Into<String>) -> Progress {
+        static NEXT_TOKEN: AtomicU64 = AtomicU64::new(1);
+
+        let progress = Progress {
+            token: format!("sg-{}", NEXT_TOKEN.fetch_add(1, Ordering::Relaxed)),
+            notifier: self.clone(),
+            done: false,
+        };
+
+        progress.send(ProgressValue::Begin {
+            title: title.into(),
+            message: None,
+            percentage: None,
+        });
+
+        progress
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+/// The same shape as LSP's `$/progress` values.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum ProgressValue {
+    Begin {
+        title: String,
+        message: Option<String>,
+        percentage: Option<u32>,
+    },
+    Report {
+        message: Option<String>,
+        percentage: Option<u32>,
+    },
+    End {
+        message: Option<String>,
+    },
+}
+
+/// A long running operation. Ends when it is dropped, so errors and cancelled requests don't
+/// leave progress behind in the editor.
+#[derive(Debug)]
+pub struct Progress {
+    token: String,
+    notifier: Notifier,
+    done: bool,
+}
+
+impl Progress {
+    pub fn report(&self, message: impl Into<String>, percentage: Option<u32>) {
+        self.send(ProgressValue::Report {
+            message: Some(message.into()),
+            percentage,
+        });
+    }
+
+    pub fn finish(mut self, message: impl Into<String>) {
+        self.done = true;
+        self.send(ProgressValue::End {
+            message: Some(message.into()),
+        });
+    }
+
+    fn send(&self, value: ProgressValue) {
+        self.notifier.send(Notification::Progress {
+            token: self.token.clone(),
+            value,
+        });
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        if !self.done {
+            self.send(ProgressValue::End { message: None });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn next(rx: &mut UnboundedReceiver<Notification>) -> (String, ProgressValue) {
+        match rx.try_recv().expect("a notification") {
+            Notification::Progress { token, value } => (token, value),
+            other => panic!("expected progress, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn progress_ends_when_dropped() {
+        let (notifier, mut rx) = Notifier::channel();
+
+        let progress = notifier.progress("Searching");
+        progress.report("halfway", Some(50));
+        drop(progress);
+
+        let (token, begin) = next(&mut rx);
+        assert!(matches!(begin, ProgressValue::Begin { title, .. } if title == "Searching"));
+
+        let (report_token, report) = next(&mut rx);
+        assert_eq!(report_token, token);
+        assert!(matches!(
+            report,
+            ProgressValue::Report {
+                percentage: Some(50),
+                ..
+            }
+        ));
+
+        let (_, end) = next(&mut rx);
+        assert!(matches!(end, ProgressValue::End { message: None }));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn finished_progress_ends_once() {
+        let (notifier, mut rx) = Notifier::channel();
+
+        notifier.progress("Listing").finish("3 entries");
+
+        next(&mut rx);
+        let (_, end) = next(&mut rx);
+        assert!(
+            matches!(end, ProgressValue::End { message: Some(message) } if message == "3 entries")
+        );
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,252 @@
+//! Typed methods for sg-nvim-agent.
+//!
+//! Every request the editor can send is a [`Method`] with its own params and result types. The
+//! [`Router`] looks methods up by name, decodes the params and encodes the result, so handlers
+//! never see JSON, and unknown methods or bad params get the matching JSON-RPC error.
+//!
+//! Params are declared with [`params!`], which also describes them as a JSON schema. The schema
+//! of every method is returned by `$/schema` (and printed by `sg-nvim-agent --schema`), so the
+//! Lua client can check its calls.
+
+use {
+    super::{LogLevel, Notification, Notifier, Progress, SecretString},
+    crate::{context::ContextOptions, network::NetworkConfig, permalink::LinkRange},
+    anyhow::Result,
+    futures::future::BoxFuture,
+    jsonrpc::{codes, Id, RPCErr},
+    once_cell::sync::Lazy,
+    serde::{de::DeserializeOwned, Serialize},
+    serde_json::{json, Map, Value},
+    std::{
+        collections::BTreeMap,
+        sync::{Arc, Mutex},
+        time::Instant,
+    },
+};
+
+/// What a handler can use besides its params.
+#[derive(Debug, Clone)]
+pub struct Context {
+    /// Id of the request being handled.
+    pub id: Id,
+
+    pub notifier: Notifier,
+
+    /// When the request last sent the editor something, see [`Activity`].
+    pub activity: Activity,
+}
+
+impl Context {
+    pub fn notify(&self, notification: Notification) {
+        self.activity.touch();
+        self.notifier.send(notification)
+    }
+
+    pub fn log(&self, level: LogLevel, message: impl Into<String>) {
+        self.notifier.log(level, message)
+    }
+
+    pub fn progress(&self, title: impl Into<String>) -> Progress {
+        self.notifier.progress(title)
+    }
+}
+
+/// When a request last sent the editor something. Requests time out once they have been quiet
+/// for too long, so a long completion isn't cut off while it is still streaming.
+#[derive(Debug, Clone)]
+pub struct Activity(Arc<Mutex<Instant>>);
+
+impl Default for Activity {
+    fn default() -> Self {
+        Self(Arc::new(Mutex::new(Instant::now())))
+    }
+}
+
+impl Activity {
+    pub fn touch(&self) {
+        *self.0.lock().expect("to unlock activity") = Instant::now();
+    }
+
+    pub fn last(&self) -> Instant {
+        *self.0.lock().expect("to unlock activity")
+    }
+}
+
+pub trait Method {
+    const NAME: &'static str;
+
+    /// Older names the method is still accepted under.
+    const ALIASES: &'static [&'static str] = &[];
+
+    type Params: DeserializeOwned + Schema + std::fmt::Debug + Send;
+    type Result: Serialize + Send;
+
+    fn handle(params: Self::Params, cx: Context) -> BoxFuture<'static, Result<Self::Result>>;
+}
+
+type Handler = Box<dyn Fn(Value, Context) -> BoxFuture<'static, Result<Value>> + Send + Sync>;
+
+#[derive(Default)]
+pub struct Router {
+    handlers: BTreeMap<&'static str, Handler>,
+    aliases: BTreeMap<&'static str, &'static str>,
+    schemas: BTreeMap<&'static str, Value>,
+}
+
+impl Router {
+    pub fn register<M: Method>(mut self) -> Self {
+        let handler: Handler = Box::new(|params, cx| {
+            Box::pin(async move {
+                // Methods without params are sent with `null` or `[]` by some clients
+                let params = match params {
+                    Value::Null => json!({}),
+                    Value::Array(array) if array.is_empty() => json!({}),
+                    params => params,
+                };
+
+                let params = serde_json::from_value::<M::Params>(params).map_err(|err| {
+                    RPCErr::new(codes::INVALID_PARAMS, format!("{}: {err}", M::NAME))
+                })?;
+                // Params can hold tokens and file contents, so only the method is logged
+                eprintln!("[sg-nvim-agent] {} {}", cx.id, M::NAME);
+
+                let result = M::handle(params, cx).await?;
+                Ok(serde_json::to_value(result)?)
+            })
+        });
+
+        if self.handlers.insert(M::NAME, handler).is_some() {
+            panic!("method registered twice: {}", M::NAME);
+        }
+
+        for alias in M::ALIASES {
+            self.aliases.insert(alias, M::NAME);
+        }
+
+        self.schemas.insert(
+            M::NAME,
+            json!({ "aliases": M::ALIASES, "params": M::Params::schema() }),
+        );
+
+        self
+    }
+
+    pub async fn call(&self, method: &str, params: Value, cx: Context) -> Result<Value> {
+        let name = self.aliases.get(method).copied().unwrap_or(method);
+        let Some(handler) = self.handlers.get(name) else {
+            let message = format!("Unknown method: {method}");
+            return Err(RPCErr::new(codes::METHOD_NOT_FOUND, message).into());
+        };
+
+        handler(params, cx).await
+    }
+
+    /// The params of every method, by method name.
+    pub fn schema(&self) -> Value {
+        json!({ "methods": self.schemas })
+    }
+}
+
+static ROUTER: Lazy<Router> = Lazy::new(super::methods::router);
+
+pub fn router() -> &'static Router {
+    &ROUTER
+}
+
+/// A (small) JSON schema for a params type.
+pub trait Schema {
+    /// Whether a field of this type must be sent.
+    const REQUIRED: bool = true;
+
+    fn schema() -> Value;
+}
+
+macro_rules! schema {
+    ($kind:literal: $($ty:ty),*) => {
+        $(impl Schema for $ty {
+            fn schema() -> Value {
+                json!({ "type": $kind })
+            }
+        })*
+    };
+}
+
+schema!("string": String, SecretString);
+schema!("integer": i32, i64, u64, usize);
+schema!("number": f64);
+schema!("boolean": bool);
+schema!("object": ContextOptions, NetworkConfig, LinkRange);
+
+impl Schema for Value {
+    fn schema() -> Value {
+        json!({})
+    }
+}
+
+impl<T: Schema> Schema for Option<T> {
+    const REQUIRED: bool = false;
+
+    fn schema() -> Value {
+        T::schema()
+    }
+}
+
+impl<T: Schema> Schema for Vec<T> {
+    fn schema() -> Value {
+        json!({ "type": "array", "items": T::schema() })
+    }
+}
+
+/// Build the schema of a params struct from its fields.
+#[doc(hidden)]
+pub fn object(fields: &[(&str, Value, bool)]) -> Value {
+    let properties = fields
+        .iter()
+        .map(|(name, schema, _)| (name.to_string(), schema.clone()))
+        .collect::<Map<_, _>>();
+    let required = fields
+        .iter()
+        .filter(|(_, _, required)| *required)
+        .map(|(name, _, _)| *name)
+        .collect::<Vec<_>>();
+
+    json!({ "type": "object", "properties": properties, "required": required })
+}
+
+/// Declare the params of a method, along with their [`Schema`].
+///
+/// Fields followed by `= default` may be left out, and use their type's default.
+macro_rules! params {
+    (
+        $(#[$meta:meta])*
+        pub struct $name:ident {
+            $(
+                $(#[doc = $doc:literal])*
+                $field:ident : $ty:ty $(= $default:ident)?
+            ),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+        pub struct $name {
+            $(
+                $(#[doc = $doc])*
+                $(#[serde($default)])?
+                pub $field: $ty,
+            )*
+        }
+
+        impl $crate::nvim::router::Schema for $name {
+            fn schema() -> serde_json::Value {
+                $crate::nvim::router::object(&[$((
+                    stringify!($field),
+                    <$ty as $crate::nvim::router::Schema>::schema(),
+                    <$ty as $crate::nvim::router::Schema>::REQUIRED
+                        $(&& stringify!($default) != "default")?,
+                )),*])
+            }
+        }
+    };
+}
+
+pub(crate) use params;
@@ -0,0 +1,282 @@
+use {
+    crate::{auth::get_instance, entry::Entry, get_commit_hash, get_external_urls},
+    anyhow::{Context, Result},
+    serde::{Deserialize, Serialize},
+    sg_types::{
+        encoding::{encode_revision, encode_url_path},
+        CodeHost, ExternalLink,
+    },
+};
+
+/// A selected region of a file, as sent by the editor.
+///
+/// Lines are 1-indexed and columns are 0-indexed (same as `nvim_win_get_cursor`).
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct LinkRange {
+    pub start_line: usize,
+    pub start_col: usize,
+    pub end_line: usize,
+    pub end_col: usize,
+}
+
+impl LinkRange {
+    fn is_point(&self) -> bool {
+        self.start_line == self.end_line && self.start_col == self.end_col
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct LinkOptions {
+    /// Pin the link to the full commit SHA, so it does not drift as the branch moves.
+    #[serde(default)]
+    pub permalink: bool,
+
+    /// Link to the code host the repository is mirrored from, instead of Sourcegraph.
+    #[serde(default)]
+    pub code_host: bool,
+}
+
+enum Target {
+    Blob(String),
+    Tree(String),
+    Root,
+}
+
+pub async fn make_link(
+    entry: &Entry,
+    ranges: &[LinkRange],
+    options: LinkOptions,
+) -> Result<String> {
+    let (remote, oid, requested, target) = match entry {
+        Entry::File(file) => (
+            &file.remote,
+            &file.oid,
+            file.revision.as_deref(),
+            Target::Blob(encode_url_path(&file.path)),
+        ),
+        Entry::Directory(dir) => (
+            &dir.remote,
+            &dir.oid,
+            dir.revision.as_deref(),
+            Target::Tree(encode_url_path(&dir.path)),
+        ),
+        Entry::Repo(repo) => (&repo.remote, &repo.oid, None, Target::Root),
+    };
+
+    // Without a permalink, link to the revision the entry was opened at (if it was opened at
+    // one), so that a link from a branch still follows that branch. The resolved oid is only
+    // used for permalinks.
+    let revision = match options.permalink {
+        true => Some(get_commit_hash(remote.0.clone(), oid.0.clone()).await?),
+        false => requested
+            .filter(|revision| !revision.is_empty())
+            .map(str::to_string),
+    };
+    let revision = revision.as_deref().map(encode_revision);
+
+    if options.code_host {
+        let links = get_external_urls(remote.0.clone()).await?;
+        let link = links
+            .iter()
+            .find(|link| link.host != CodeHost::Other)
+            .context("Repository is not mirrored from a supported code host")?;
+
+        return Ok(code_host_url(link, revision.as_deref(), &target, ranges));
+    }
+
+    Ok(sourcegraph_url(
+        &get_instance(Some(&remote.0)).await?.endpoint,
+        &remote.0,
+        revision.as_deref(),
+        &target,
+        ranges,
+    ))
+}
+
+fn sourcegraph_url(
+    endpoint: &str,
+    remote: &str,
+    revision: Option<&str>,
+    target: &Target,
+    ranges: &[LinkRange],
+) -> String {
+    let repo = match revision {
+        Some(revision) => format!("{endpoint}/{remote}@{revision}"),
+        None => format!("{endpoint}/{remote}"),
+    };
+
+    match target {
+        Target::Root => repo,
+        Target::Tree(path) => format!("{repo}/-/tree/{path}"),
+        Target::Blob(path) if ranges.is_empty() => format!("{repo}/-/blob/{path}"),
+        Target::Blob(path) => {
+            let fragment = ranges
+                .iter()
+                .map(|range| {
+                    if range.is_point() {
+                        if range.start_col == 0 {
+                            format!("L{}", range.start_line)
+                        } else {
+                            format!("L{}:{}", range.start_line, range.start_col)
+                        }
+                    } else {
+                        format!(
+                            "L{}:{}-{}:{}",
+                            range.start_line, range.start_col, range.end_line, range.end_col
+                        )
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join(",");
+
+            format!("{repo}/-/blob/{path}?{fragment}")
+        }
+    }
+}
+
+fn code_host_url(
+    link: &ExternalLink,
+    revision: Option<&str>,
+    target: &Target,
+    ranges: &[LinkRange],
+) -> String {
+    let base = link.url.trim_end_matches('/');
+    let rev = revision.unwrap_or("HEAD");
+
+    let url = match target {
+        Target::Root => match (link.host, revision) {
+            (CodeHost::GitHub, Some(revision)) => format!("{base}/tree/{revision}"),
+            (CodeHost::GitLab, Some(revision)) => format!("{base}/-/tree/{revision}"),
+            (CodeHost::BitbucketCloud, Some(revision)) => format!("{base}/src/{revision}"),
+            (CodeHost::BitbucketServer, Some(revision)) => format!("{base}?at={revision}"),
+            _ => base.to_string(),
+        },
+        Target::Blob(path) | Target::Tree(path) => {
+            let kind = match target {
+                Target::Blob(_) => "blob",
+                _ => "tree",
+            };
+
+            match link.host {
+                CodeHost::GitHub => format!("{base}/{kind}/{rev}/{path}"),
+                CodeHost::GitLab => format!("{base}/-/{kind}/{rev}/{path}"),
+                CodeHost::BitbucketCloud => format!("{base}/src/{rev}/{path}"),
+                CodeHost::BitbucketServer => match revision {
+                    Some(revision) => format!("{base}/{path}?at={revision}"),
+                    None => format!("{base}/{path}"),
+                },
+                CodeHost::Other => base.to_string(),
+            }
+        }
+    };
+
+    if !matches!(target, Target::Blob(_)) || ranges.is_empty() {
+        return url;
+    }
+
+    let lines = |range: &LinkRange, sep: &str| {
+        if range.start_line == range.end_line {
+            range.start_line.to_string()
+        } else {
+            format!("{}{sep}{}", range.start_line, range.end_line)
+        }
+    };
+
+    // GitHub and GitLab can only highlight a single range, so we use the first one.
+    match link.host {
+        CodeHost::GitHub => {
+            let range = &ranges[0];
+            if range.start_line == range.end_line {
+                format!("{url}#L{}", range.start_line)
+            } else {
+                format!("{url}#L{}-L{}", range.start_line, range.end_line)
+            }
+        }
+        CodeHost::GitLab => format!("{url}#L{}", lines(&ranges[0], "-")),
+        CodeHost::BitbucketServer => {
+            let fragment = ranges.iter().map(|r| lines(r, "-")).collect::<Vec<_>>();
+            format!("{url}#{}", fragment.join(","))
+        }
+        CodeHost::BitbucketCloud => {
+            let fragment = ranges.iter().map(|r| lines(r, ":")).collect::<Vec<_>>();
+            format!("{url}#lines-{}", fragment.join(","))
+        }
+        CodeHost::Other => url,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(start_line: usize, end_line: usize) -> LinkRange {
+        LinkRange {
+            start_line,
+            start_col: 0,
+            end_line,
+            end_col: 0,
+        }
+    }
+
+    fn github() -> ExternalLink {
+        ExternalLink {
+            url: "https://github.com/sourcegraph/sg.nvim/".to_string(),
+            host: CodeHost::GitHub,
+        }
+    }
+
+    #[test]
+    fn sourcegraph_links_keep_the_revision() {
+        let target = Target::Blob("lua/sg.lua".to_string());
+        assert_eq!(
+            sourcegraph_url(
+                "https://sourcegraph.com",
+                "github.com/sourcegraph/sg.nvim",
+                Some("my-branch"),
+                &target,
+                &[range(3, 3)],
+            ),
+            "https://sourcegraph.com/github.com/sourcegraph/sg.nvim@my-branch/-/blob/lua/sg.lua?L3"
+        );
+
+        assert_eq!(
+            sourcegraph_url(
+                "https://sourcegraph.com",
+                "github.com/sourcegraph/sg.nvim",
+                None,
+                &Target::Root,
+                &[],
+            ),
+            "https://sourcegraph.com/github.com/sourcegraph/sg.nvim"
+        );
+    }
+
+    #[test]
+    fn paths_are_encoded_a_segment_at_a_time() {
+        let target = Target::Blob(encode_url_path("docs/a b#1.md"));
+        assert_eq!(
+            sourcegraph_url(
+                "https://sourcegraph.com",
+                "github.com/a/b",
+                None,
+                &target,
+                &[]
+            ),
+            "https://sourcegraph.com/github.com/a/b/-/blob/docs/a%20b%231.md"
+        );
+    }
+
+    #[test]
+    fn code_host_links_keep_the_revision() {
+        let target = Target::Blob("lua/sg.lua".to_string());
+        assert_eq!(
+            code_host_url(&github(), Some("my-branch"), &target, &[range(3, 5)]),
+            "https://github.com/sourcegraph/sg.nvim/blob/my-branch/lua/sg.lua#L3-L5"
+        );
+
+        assert_eq!(
+            code_host_url(&github(), None, &Target::Tree("lua".to_string()), &[]),
+            "https://github.com/sourcegraph/sg.nvim/tree/HEAD/lua"
+        );
+    }
+}